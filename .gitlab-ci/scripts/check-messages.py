@@ -0,0 +1,56 @@
+#!/usr/bin/env python3
+# SPDX-License-Identifier: GPL-2.0-only OR GPL-3.0-only OR LicenseRef-KDE-Accepted-GPL
+# SPDX-FileCopyrightText: None
+import ast
+import os
+import re
+import subprocess
+import sys
+import tempfile
+from pathlib import Path
+
+# Runs Messages.sh the way scripty does and checks that every tr()/trn() literal in btrfs-migrator made it into the
+# template. xgettext only warns when it misparses a file and happily drops whatever came after.
+
+COMMENT = re.compile(r"^\s*//.*$", re.MULTILINE)
+CALL = re.compile(r'\btrn?\(\s*("(?:[^"\\]|\\.)*")', re.DOTALL)
+
+
+def msgids(pot: str) -> set[str]:
+    ids = set()
+    current = None
+    for line in pot.splitlines() + [""]:
+        if line.startswith('"') and current is not None:
+            current.append(line)
+            continue
+        if current is not None:
+            # xgettext wraps long messages into adjacent literals.
+            ids.add("".join(ast.literal_eval(part) for part in current))
+            current = None
+        if line.startswith(("msgid ", "msgid_plural ")):
+            current = [line.split(" ", 1)[1]]
+    return ids
+
+
+def main() -> None:
+    root = Path(__file__).resolve().parents[2]
+    with tempfile.TemporaryDirectory() as podir:
+        env = dict(os.environ, XGETTEXT=os.environ.get("XGETTEXT", "xgettext"), podir=podir)
+        result = subprocess.run(["bash", "Messages.sh"], cwd=root, env=env, capture_output=True, text=True)
+        sys.stderr.write(result.stderr)
+        if result.returncode != 0 or "warning" in result.stderr:
+            sys.exit("Messages.sh failed or xgettext warned")
+        extracted = msgids((Path(podir) / "kde-linux.pot").read_text())
+
+    missing = []
+    for source in sorted((root / "btrfs-migrator/src").glob("*.rs")):
+        for literal in CALL.findall(COMMENT.sub("", source.read_text())):
+            if ast.literal_eval(literal) not in extracted:
+                missing.append(f"{source.relative_to(root)}: {literal}")
+    if missing:
+        sys.exit("Not extracted:\n" + "\n".join(missing))
+    print(f"{len(extracted)} messages extracted")
+
+
+if __name__ == "__main__":
+    main()
@@ -1,4 +1,6 @@
 #! /usr/bin/env bash
 # SPDX-FileCopyrightText: None
 # SPDX-License-Identifier: CC0-1.0
-$XGETTEXT --language=Python mkosi.extra/usr/lib/command-not-found-handler.py --output=$podir/kde-linux.pot
+$XGETTEXT --language=Python mkosi.extra/usr/lib/command-not-found-handler.py mkosi.extra/usr/lib/migration-report --output=$podir/kde-linux.pot
+# The Rust parser needs gettext 0.24 or later.
+$XGETTEXT --language=Rust --keyword=tr --keyword=trn:1,2 --join-existing btrfs-migrator/src/*.rs --output=$podir/kde-linux.pot
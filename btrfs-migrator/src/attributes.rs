@@ -0,0 +1,28 @@
+// SPDX-License-Identifier: GPL-2.0-only OR GPL-3.0-only OR LicenseRef-KDE-Accepted-GPL
+// SPDX-FileCopyrightText: None
+
+// chattr(1) without needing chattr in the initrd.
+
+use std::{fs::File, io, os::fd::AsRawFd, path::Path};
+
+// linux/fs.h, libc does not have it.
+const FS_NOCOW_FL: libc::c_int = 0x0080_0000;
+
+// No copy-on-write, which on btrfs also means no compression. Only takes effect on empty files, on directories it is
+// inherited by whatever gets created inside afterwards.
+pub fn set_nocow(path: &Path) -> io::Result<()> {
+    let file = File::open(path)?;
+    // Declared as long, but the kernel reads and writes an int.
+    let mut flags: libc::c_int = 0;
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if flags & FS_NOCOW_FL != 0 {
+        return Ok(());
+    }
+    flags |= FS_NOCOW_FL;
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_SETFLAGS, &flags) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,241 @@
+// SPDX-License-Identifier: GPL-2.0-only OR GPL-3.0-only OR LicenseRef-KDE-Accepted-GPL
+// SPDX-FileCopyrightText: None
+
+// When the migration fails the user ends up in an emergency shell, and whatever could tell us why is gone with the
+// next reboot. So we leave a diagnostic bundle in kde-linux-migration-failures/<time>/ on the btrfs top level, or on
+// the ESP when btrfs has no room for it. collect-logs picks it up from either place.
+
+use std::{
+    error::Error,
+    fmt::Write as _,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    durability::fsync_dir,
+    help::{self, Class},
+    journal, subvolume,
+};
+
+const DIR: &str = "kde-linux-migration-failures";
+// Older bundles are deleted, a boot loop should not fill the disk.
+const KEEP: usize = 5;
+// More entries than this per directory in the tree summary are only counted.
+const TREE_ENTRIES: usize = 50;
+
+const LOADER_DEVICE_PART_UUID: &str =
+    "/sys/firmware/efi/efivars/LoaderDevicePartUUID-4a67b082-0a4c-41cf-b6c7-440b29bb8c4f";
+const ESP_MOUNT: &str = "/run/kde-linux-migration-esp";
+
+fn run(program: &str, args: &[&str]) -> Vec<u8> {
+    let mut text = format!("# {program} {}\n\n", args.join(" ")).into_bytes();
+    match Command::new(program).args(args).output() {
+        Ok(output) => {
+            text.extend(output.stdout);
+            text.extend(output.stderr);
+        }
+        Err(e) => text.extend(format!("Failed to run {program}: {e}\n").into_bytes()),
+    }
+    text
+}
+
+fn error_chain(error: &(dyn Error + 'static)) -> Vec<u8> {
+    let mut text = format!("Error: {error}\n");
+    let mut source = error.source();
+    while let Some(cause) = source {
+        let _ = writeln!(text, "Caused by: {cause}");
+        source = cause.source();
+    }
+    let _ = writeln!(text, "Diagnostic code: {}", help::classify(error).code());
+    if let Some(phase) = journal::current_phase() {
+        let _ = writeln!(text, "Phase: {phase}");
+    }
+    text.into_bytes()
+}
+
+fn tree(text: &mut String, path: &Path, depth: usize, indent: usize) {
+    let mut entries = match fs::read_dir(path) {
+        Ok(entries) => entries.flatten().collect::<Vec<_>>(),
+        Err(e) => {
+            let _ = writeln!(text, "{:indent$}(unreadable: {e})", "");
+            return;
+        }
+    };
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries.iter().take(TREE_ENTRIES) {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let Ok(metadata) = entry.path().symlink_metadata() else {
+            let _ = writeln!(text, "{:indent$}{name} (vanished)", "");
+            continue;
+        };
+        if metadata.is_dir() {
+            let kind = if subvolume(&entry.path()).unwrap_or(false) {
+                " (subvolume)"
+            } else {
+                ""
+            };
+            let _ = writeln!(text, "{:indent$}{name}/{kind}", "");
+            if depth > 1 {
+                tree(text, &entry.path(), depth - 1, indent + 2);
+            }
+        } else if metadata.is_symlink() {
+            let target = fs::read_link(entry.path()).unwrap_or_default();
+            let _ = writeln!(text, "{:indent$}{name} -> {}", "", target.display());
+        } else {
+            let _ = writeln!(text, "{:indent$}{name} ({} bytes)", "", metadata.len());
+        }
+    }
+    if entries.len() > TREE_ENTRIES {
+        let _ = writeln!(
+            text,
+            "{:indent$}… {} more",
+            "",
+            entries.len() - TREE_ENTRIES
+        );
+    }
+}
+
+// The directories the migrations work in, and what is in them right now.
+fn tree_summary(root: &Path) -> Vec<u8> {
+    let mut text = String::new();
+    for (dir, depth) in [
+        ("", 1),
+        ("@system", 1),
+        ("@system.import", 2),
+        ("@system/home", 2),
+        ("@system/home.v3tmp", 2),
+        ("@system/home.v3old", 2),
+        ("@system/home.v2tmp", 2),
+        ("@system/home.v2old", 2),
+        ("@system/var/lib", 1),
+    ] {
+        let path = root.join(dir);
+        if !path.exists() {
+            continue;
+        }
+        let _ = writeln!(text, "{}:", path.display());
+        tree(&mut text, &path, depth, 2);
+        text.push('\n');
+    }
+    text.into_bytes()
+}
+
+fn fstabs(root: &Path) -> Vec<u8> {
+    let mut text = Vec::new();
+    for fstab in [
+        "@system/etc/fstab",
+        "@system/etc/crypttab",
+        "@etc-overlay/upper/fstab",
+        "@etc-overlay/upper/crypttab",
+    ] {
+        if let Ok(content) = fs::read(root.join(fstab)) {
+            text.extend(format!("# {fstab}\n\n").into_bytes());
+            text.extend(content);
+            text.push(b'\n');
+        }
+    }
+    text
+}
+
+fn store(base: &Path, name: &str, files: &[(&str, Vec<u8>)]) -> io::Result<PathBuf> {
+    let dir = base.join(DIR).join(name);
+    fs::create_dir_all(&dir)?;
+    for (file, content) in files {
+        let mut file = File::create(dir.join(file))?;
+        file.write_all(content)?;
+        file.sync_all()?;
+    }
+    fsync_dir(&dir)?;
+    fsync_dir(&base.join(DIR))?;
+
+    // Names are timestamps, so the oldest sort first.
+    let mut bundles: Vec<PathBuf> = fs::read_dir(base.join(DIR))?
+        .flatten()
+        .map(|entry| entry.path())
+        .collect();
+    bundles.sort();
+    while bundles.len() > KEEP {
+        let _ = fs::remove_dir_all(bundles.remove(0));
+    }
+    Ok(dir)
+}
+
+// The partition the boot loader was started from.
+fn esp_device() -> Option<PathBuf> {
+    let data = fs::read(LOADER_DEVICE_PART_UUID).ok()?;
+    // Four bytes of attributes, then a NUL terminated UTF-16LE string.
+    let utf16: Vec<u16> = data
+        .get(4..)?
+        .chunks_exact(2)
+        .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+        .take_while(|&unit| unit != 0)
+        .collect();
+    let uuid = String::from_utf16(&utf16).ok()?.to_lowercase();
+    Some(PathBuf::from(format!("/dev/disk/by-partuuid/{uuid}")))
+}
+
+fn store_on_esp(name: &str, files: &[(&str, Vec<u8>)]) -> Result<PathBuf, Box<dyn Error>> {
+    let device = esp_device().ok_or("Cannot tell which partition is the ESP")?;
+    fs::create_dir_all(ESP_MOUNT)?;
+    let status = Command::new("mount")
+        .arg("--types")
+        .arg("vfat")
+        .arg(&device)
+        .arg(ESP_MOUNT)
+        .status()?;
+    if !status.success() {
+        return Err(format!("Failed to mount {device:?}").into());
+    }
+    let result = store(Path::new(ESP_MOUNT), name, files);
+    let _ = Command::new("umount").arg(ESP_MOUNT).status();
+    Ok(result?)
+}
+
+pub fn write(root: &Path, error: &(dyn Error + 'static)) {
+    // Stopped on purpose, by a signal or the policy. There is nothing to diagnose.
+    if matches!(help::classify(error), Class::Cancelled | Class::Policy) {
+        return;
+    }
+    let name = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+        .to_string();
+    let root_str = root.to_string_lossy();
+    let files = [
+        ("error.txt", error_chain(error)),
+        (
+            "subvolumes.txt",
+            run("btrfs", &["subvolume", "list", "-a", "-p", "-u", &root_str]),
+        ),
+        ("tree.txt", tree_summary(root)),
+        (
+            "journal.txt",
+            run(
+                "journalctl",
+                &["--boot=0", "--no-pager", "--output=short-monotonic"],
+            ),
+        ),
+        (
+            "mountinfo.txt",
+            fs::read("/proc/self/mountinfo").unwrap_or_default(),
+        ),
+        ("fstab.txt", fstabs(root)),
+        ("cmdline.txt", fs::read("/proc/cmdline").unwrap_or_default()),
+    ];
+
+    let stored = store(root, &name, &files).or_else(|e| {
+        eprintln!(
+            "Failed to write the diagnostic bundle to the btrfs top level: {e}. Trying the ESP."
+        );
+        store_on_esp(&name, &files)
+    });
+    match stored {
+        Ok(dir) => eprintln!("Diagnostic bundle written to {dir:?}. collect-logs includes it."),
+        Err(e) => eprintln!("Failed to write the diagnostic bundle: {e}"),
+    }
+}
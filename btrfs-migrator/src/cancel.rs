@@ -0,0 +1,68 @@
+// SPDX-License-Identifier: GPL-2.0-only OR GPL-3.0-only OR LicenseRef-KDE-Accepted-GPL
+// SPDX-FileCopyrightText: None
+
+// SIGINT and SIGTERM only record that we should stop. The migration then polls checkpoint() between steps, at points
+// where stopping leaves the filesystem in a state the next run knows how to pick up from. Dying halfway through a
+// copy or between two renames is what the recovery code exists for, but there is no reason to exercise it on every
+// Ctrl-C or systemd job timeout.
+
+use std::{
+    error::Error,
+    io,
+    os::fd::{AsRawFd, BorrowedFd},
+    sync::{
+        Arc, LazyLock,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use signal_hook::consts::{SIGINT, SIGTERM};
+
+use crate::{help::Class, notify};
+
+// How often to look for SIGINT and SIGTERM while waiting for the user.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// The number of the signal that requested cancellation, 0 if none did.
+static REQUESTED: LazyLock<Arc<AtomicUsize>> = LazyLock::new(|| Arc::new(AtomicUsize::new(0)));
+
+pub fn install_handlers() -> io::Result<()> {
+    for signal in [SIGINT, SIGTERM] {
+        signal_hook::flag::register_usize(signal, Arc::clone(&REQUESTED), signal as usize)?;
+    }
+    Ok(())
+}
+
+// Bail out if a signal arrived. `next_boot` tells the user what state we leave behind and what happens next time.
+pub fn checkpoint(next_boot: &str) -> Result<(), Box<dyn Error>> {
+    notify::progress();
+    let signal = match REQUESTED.load(Ordering::SeqCst) as i32 {
+        0 => return Ok(()),
+        SIGINT => "SIGINT",
+        SIGTERM => "SIGTERM",
+        _ => "a signal",
+    };
+    eprintln!("Received {signal}. Stopped at a safe point, the filesystem is consistent.");
+    eprintln!("{next_boot}");
+    Err(Class::Cancelled.error(format!("Migration cancelled by {signal}")))
+}
+
+// Whether `fd` has something to read within POLL_INTERVAL. A closed connection counts, the read then fails. The
+// handlers restart reads, so waiting for the user goes through here with checkpoint() between the polls.
+pub fn readable(fd: BorrowedFd) -> io::Result<bool> {
+    let mut pollfd = libc::pollfd {
+        fd: fd.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    match unsafe { libc::poll(&mut pollfd, 1, POLL_INTERVAL.as_millis() as libc::c_int) } {
+        -1 => match io::Error::last_os_error() {
+            // A signal, checked for by the caller.
+            e if e.kind() == io::ErrorKind::Interrupted => Ok(false),
+            e => Err(e),
+        },
+        0 => Ok(false),
+        _ => Ok(true),
+    }
+}
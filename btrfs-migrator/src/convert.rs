@@ -0,0 +1,163 @@
+// SPDX-License-Identifier: GPL-2.0-only OR GPL-3.0-only OR LicenseRef-KDE-Accepted-GPL
+// SPDX-FileCopyrightText: None
+
+// Turning a directory inside @system into a subvolume of its own, so that snapshots of @system leave it out. Staged
+// like run_v3: the content is reflinked into the subvolume <name>.subvoltmp next to it, which then gets exchanged
+// with the directory. At any point in time the directory is either the complete old or the complete new one. A
+// leftover staging subvolume means we stopped before the exchange and is thrown away, a leftover plain directory
+// means we stopped after it and only the cleanup is missing.
+
+use std::{
+    error::Error,
+    ffi::OsString,
+    fs,
+    os::unix::fs::{MetadataExt, chown},
+    path::{Path, PathBuf},
+};
+
+use libbtrfsutil::{CreateSubvolumeOptions, DeleteSubvolumeOptions};
+
+use crate::{
+    attributes::set_nocow,
+    cancel::checkpoint,
+    cp,
+    durability::{fsync_dir, rename_exchange, sync_fs},
+    fault,
+    help::{Class, Context},
+    mountinfo::ensure_unmounted,
+    remove_tree, snapshot_nested_subvolumes, subvolume,
+};
+
+const STAGING_SUFFIX: &str = ".subvoltmp";
+
+// Where `dir` gets staged.
+pub fn staging(dir: &Path) -> PathBuf {
+    let mut name = OsString::from(dir.file_name().unwrap_or_default());
+    name.push(STAGING_SUFFIX);
+    dir.with_file_name(name)
+}
+
+// Whether `dir` is a subvolume and no attempt to make it one is left unfinished.
+pub fn converted(dir: &Path) -> bool {
+    subvolume(dir).unwrap_or(false) && !staging(dir).exists()
+}
+
+// Picks up after an earlier attempt on `dir` that stopped halfway.
+fn recover(dir: &Path) -> Result<(), Box<dyn Error>> {
+    let tmp = staging(dir);
+    ensure_unmounted(dir)?;
+    ensure_unmounted(&tmp)?;
+    if !tmp.exists() {
+        return Ok(());
+    }
+    let parent = dir.parent().unwrap_or(Path::new("/"));
+    if subvolume(&tmp)? {
+        if subvolume(dir)? {
+            return Err(Class::Inconsistent.error(format!(
+                "Both {dir:?} and {tmp:?} are subvolumes. Refusing to touch either."
+            )));
+        }
+        println!("Deleting incomplete staging subvolume {tmp:?}");
+        DeleteSubvolumeOptions::new()
+            .recursive(true)
+            .delete(&tmp)
+            .context(|| format!("Failed to delete {tmp:?}"))?;
+    } else {
+        // Exchanged already, so this is the old directory.
+        if !subvolume(dir)? {
+            return Err(Class::Inconsistent.error(format!(
+                "Neither {dir:?} nor {tmp:?} is a subvolume. Refusing to touch either."
+            )));
+        }
+        println!("Deleting leftover {tmp:?} from a previous run");
+        remove_tree(&tmp)?;
+    }
+    fsync_dir(parent).context(|| format!("Failed to sync {parent:?}"))?;
+    Ok(())
+}
+
+// Makes `dir` a subvolume with the same content. When missing it is created with the owner and mode of its parent.
+// `nocow` are directories below `dir` that get copy-on-write disabled, along with the directories directly in them.
+// `fault` prefixes the fault injection points.
+pub fn into_subvolume(
+    dir: &Path,
+    nocow: &[&str],
+    fault: &str,
+    next_boot: &str,
+) -> Result<(), Box<dyn Error>> {
+    recover(dir)?;
+    let parent = dir.parent().unwrap_or(Path::new("/"));
+    if !dir.exists() {
+        println!("Creating subvolume {dir:?}");
+        CreateSubvolumeOptions::new()
+            .create(dir)
+            .context(|| format!("Failed to create subvolume {dir:?}"))?;
+        let metadata = fs::metadata(parent)?;
+        chown(dir, Some(metadata.uid()), Some(metadata.gid()))?;
+        fs::set_permissions(dir, metadata.permissions())?;
+        fsync_dir(parent).context(|| format!("Failed to sync {parent:?}"))?;
+        return Ok(());
+    }
+    if subvolume(dir)? {
+        println!("{dir:?} is a subvolume already");
+        return Ok(());
+    }
+
+    let tmp = staging(dir);
+    println!("Creating staging subvolume {tmp:?}");
+    CreateSubvolumeOptions::new()
+        .create(&tmp)
+        .context(|| format!("Failed to create subvolume {tmp:?}"))?;
+    fault::inject(&format!("{fault}-after-create-tmp"))?;
+
+    // Created NOCOW ahead of the copy, so that the files copied into them are as well. A NOCOW file cannot share
+    // extents, these get copied in full rather than reflinked.
+    for relative in nocow {
+        let src = dir.join(relative);
+        if !src.is_dir() {
+            continue;
+        }
+        let mut dirs = vec![PathBuf::from(relative)];
+        for entry in fs::read_dir(&src)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                dirs.push(Path::new(relative).join(entry.file_name()));
+            }
+        }
+        for relative in dirs {
+            let dst = tmp.join(&relative);
+            println!("Disabling copy-on-write for {dst:?}");
+            fs::create_dir_all(&dst)?;
+            set_nocow(&dst).context(|| format!("Failed to disable copy-on-write for {dst:?}"))?;
+        }
+    }
+
+    // Everything else is reflinked, so it costs next to no time or space.
+    println!("Copying {dir:?} to {tmp:?}");
+    let cp_result = cp()
+        .arg("--recursive")
+        .arg("--archive")
+        .arg("--reflink=auto")
+        .arg(format!("{}/.", dir.display()))
+        .arg(format!("{}/.", tmp.display()))
+        .status()
+        .context(|| "Failed to run cp")?;
+    if !cp_result.success() {
+        return Err(Class::Copy.error(format!("Failed to copy {dir:?} to {tmp:?}")));
+    }
+    snapshot_nested_subvolumes(dir, &tmp)?;
+
+    // Last chance to stop. Past the exchange only the cleanup is left.
+    checkpoint(next_boot)?;
+    sync_fs(parent).context(|| format!("Failed to sync {parent:?}"))?;
+    fault::inject(&format!("{fault}-before-exchange"))?;
+    println!("Exchanging {tmp:?} and {dir:?}");
+    rename_exchange(&tmp, dir).context(|| format!("Failed to exchange {tmp:?} and {dir:?}"))?;
+    fsync_dir(parent).context(|| format!("Failed to sync {parent:?}"))?;
+    fault::inject(&format!("{fault}-after-exchange"))?;
+
+    println!("Deleting the old directory, now at {tmp:?}");
+    remove_tree(&tmp)?;
+    fsync_dir(parent).context(|| format!("Failed to sync {parent:?}"))?;
+    Ok(())
+}
@@ -0,0 +1,49 @@
+// SPDX-License-Identifier: GPL-2.0-only OR GPL-3.0-only OR LicenseRef-KDE-Accepted-GPL
+// SPDX-FileCopyrightText: None
+
+// Barriers for the rename dances. A rename is only as good as the data it publishes: without flushing first a
+// power cut can leave us with the new name pointing at a half written tree, and without fsyncing the parent the
+// rename itself may not have made it to disk when we go on to delete the old copy.
+
+use std::{
+    ffi::CString,
+    fs::File,
+    io,
+    os::{fd::AsRawFd, unix::ffi::OsStrExt},
+    path::Path,
+};
+
+// Flush everything on the filesystem backing `path`. Also commits the btrfs transaction, so subvolume creation and
+// snapshots are on disk afterwards.
+pub fn sync_fs(path: &Path) -> io::Result<()> {
+    let file = File::open(path)?;
+    if unsafe { libc::syncfs(file.as_raw_fd()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Make the directory entries of `path` durable, i.e. renames into or out of it.
+pub fn fsync_dir(path: &Path) -> io::Result<()> {
+    File::open(path)?.sync_all()
+}
+
+// Atomically swap `a` and `b`. Both have to exist. Unlike two consecutive renames there is no point in time where
+// either name is missing.
+pub fn rename_exchange(a: &Path, b: &Path) -> io::Result<()> {
+    let a = CString::new(a.as_os_str().as_bytes())?;
+    let b = CString::new(b.as_os_str().as_bytes())?;
+    let ret = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            a.as_ptr(),
+            libc::AT_FDCWD,
+            b.as_ptr(),
+            libc::RENAME_EXCHANGE,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
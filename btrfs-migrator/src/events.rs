@@ -0,0 +1,131 @@
+// SPDX-License-Identifier: GPL-2.0-only OR GPL-3.0-only OR LicenseRef-KDE-Accepted-GPL
+// SPDX-FileCopyrightText: None
+
+// --output=jsonl: one JSON object per line on stdout for whoever follows the migration programmatically, e.g.
+// openQA. The human output moves to stderr so stdout carries nothing else.
+//
+// Every event has
+//   "schema": SCHEMA, "event": one of the below, "timestamp_usec": wall clock time in µs since the epoch
+// and, depending on "event":
+//   started          "from_layout", "to_layout"
+//   phase-started    "phase"
+//   phase-finished   "phase", "duration_usec", "path" and "bytes" (both may be null)
+//   progress         "percent", 0 to 100
+//   status           "message", what the splash shows. Translated, so only for display.
+//   warning          "message", "path" (may be null), "phase" (null outside of a phase)
+//   prompt           "summary", "prompt". The migrator waits for an answer on the splash or the console.
+//   prompt-answered  "prompt", "answer" (true or false), "source" (policy, splash or console)
+//   result           "result" (finished, skipped or failed), "message", and when failed "diagnostic", "help_url"
+//                    and "phase"
+// "result" is the last event of a run, unless the migrator crashed.
+
+use std::{
+    fs::File,
+    io::{self, Write},
+    os::fd::{AsFd, AsRawFd},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{Value, json};
+
+// Bump when changing the meaning of existing keys or events. Adding keys or events is fine, consumers are expected to
+// ignore what they don't know.
+pub const SCHEMA: u32 = 1;
+
+static OUTPUT: Mutex<Option<File>> = Mutex::new(None);
+
+// Keep the real stdout for the events and point fd 1 at stderr, which also covers the cp and btrfs we spawn.
+pub fn enable() -> io::Result<()> {
+    let stdout = File::from(io::stdout().as_fd().try_clone_to_owned()?);
+    io::stdout().flush()?;
+    if unsafe { libc::dup2(io::stderr().as_raw_fd(), io::stdout().as_raw_fd()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    *OUTPUT.lock().unwrap() = Some(stdout);
+    Ok(())
+}
+
+fn emit(event: &str, fields: Value) {
+    let mut output = OUTPUT.lock().unwrap();
+    let Some(file) = output.as_mut() else {
+        return;
+    };
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0);
+    let mut value = json!({
+        "schema": SCHEMA,
+        "event": event,
+        "timestamp_usec": timestamp,
+    });
+    if let (Some(value), Value::Object(fields)) = (value.as_object_mut(), fields) {
+        value.extend(fields);
+    }
+    // A consumer that went away is no reason to stop migrating.
+    let _ = writeln!(file, "{value}").and_then(|_| file.flush());
+}
+
+pub fn started(from: u8, to: u8) {
+    emit("started", json!({ "from_layout": from, "to_layout": to }));
+}
+
+pub fn phase_started(phase: &str) {
+    emit("phase-started", json!({ "phase": phase }));
+}
+
+pub fn phase_finished(phase: &str, duration_usec: u128, path: Option<String>, bytes: Option<u64>) {
+    emit(
+        "phase-finished",
+        json!({
+            "phase": phase,
+            "duration_usec": duration_usec as u64,
+            "path": path,
+            "bytes": bytes,
+        }),
+    );
+}
+
+pub fn progress(percent: u8) {
+    emit("progress", json!({ "percent": percent }));
+}
+
+pub fn status(message: &str) {
+    emit("status", json!({ "message": message }));
+}
+
+pub fn warning(message: &str, path: Option<String>, phase: Option<String>) {
+    emit(
+        "warning",
+        json!({ "message": message, "path": path, "phase": phase }),
+    );
+}
+
+pub fn prompt(summary: &str, prompt: &str) {
+    emit("prompt", json!({ "summary": summary, "prompt": prompt }));
+}
+
+pub fn prompt_answered(prompt: &str, answer: bool, source: &str) {
+    emit(
+        "prompt-answered",
+        json!({ "prompt": prompt, "answer": answer, "source": source }),
+    );
+}
+
+pub fn result(result: &str, message: &str) {
+    emit("result", json!({ "result": result, "message": message }));
+}
+
+pub fn failed(message: &str, diagnostic: &str, help_url: &str, phase: Option<String>) {
+    emit(
+        "result",
+        json!({
+            "result": "failed",
+            "message": message,
+            "diagnostic": diagnostic,
+            "help_url": help_url,
+            "phase": phase,
+        }),
+    );
+}
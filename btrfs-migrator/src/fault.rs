@@ -0,0 +1,29 @@
+// SPDX-License-Identifier: GPL-2.0-only OR GPL-3.0-only OR LicenseRef-KDE-Accepted-GPL
+// SPDX-FileCopyrightText: None
+
+// Named points where the migration can be made to fail on purpose, so the recovery paths get exercised without
+// pulling the plug. Set KDE_LINUX_MIGRATOR_FAULT to a point name to return an error there, or to <name>:abort to
+// die on the spot like a crash would: no unwinding, no deferred unmounts, no cleanup. In the initrd it can be set
+// with systemd.setenv= on the kernel command line. tests/fault-injection.sh drives every point.
+
+use std::{env, error::Error, process};
+
+const ENV: &str = "KDE_LINUX_MIGRATOR_FAULT";
+
+pub fn inject(point: &str) -> Result<(), Box<dyn Error>> {
+    let Ok(spec) = env::var(ENV) else {
+        return Ok(());
+    };
+    let (name, mode) = spec.split_once(':').unwrap_or((&spec, "error"));
+    if name != point {
+        return Ok(());
+    }
+    match mode {
+        "abort" => {
+            eprintln!("Injected fault: aborting at {point}");
+            process::abort();
+        }
+        "error" => Err(format!("Injected fault at {point}").into()),
+        _ => Err(format!("Invalid {ENV} mode {mode:?}, expected error or abort").into()),
+    }
+}
@@ -0,0 +1,1275 @@
+// SPDX-License-Identifier: GPL-2.0-only OR GPL-3.0-only OR LicenseRef-KDE-Accepted-GPL
+// SPDX-FileCopyrightText: None
+
+// The v1 fstab in @etc-overlay/upper, sorted into entries that carry over into @system/etc as they are, entries that
+// have to go because what they mount is part of @system now, and entries only the user can judge. Parsed by hand so
+// comments and formatting survive the rewrite.
+//
+// Mounting a v1 data subvolume by subvol= or subvolid= finds the stale original rather than its snapshot in @system.
+// fstab entries get pointed at the snapshot, or disabled where @system has it in place anyway. The same goes for the
+// subvolumes nested in them, the snapshots are recursive. @home is disabled wherever it is mounted, run_v3 turns its
+// snapshot into a plain directory that subvol= cannot mount. Every change goes into the log as a diff.
+//
+// The .mount and .automount units in the overlay's systemd/system are sorted the same way. Units get pointed at the
+// snapshot too, disabled ones are renamed out of the way so nothing pulls them in anymore.
+//
+// Swapfiles that end up in @system are recreated in a swap subvolume, see swap.rs, and their entries follow them. Only
+// those that were, an entry for a swapfile that is missing stays as it is.
+//
+// crypttab comes along unchanged. Its devices count as data disks where fstab mounts them, and like those need a
+// name that does not change between boots. Keyfiles inside the data subvolumes move with them, which deserves a
+// warning.
+
+use std::{
+    fmt::{Display, Write as _},
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    slice,
+};
+
+use crate::{SUBVOL_TARGETS, durability::fsync_dir, i18n::tr, journal, mountinfo, swap};
+
+const NETWORK_TYPES: [&str; 9] = [
+    "nfs",
+    "nfs4",
+    "cifs",
+    "smb3",
+    "sshfs",
+    "fuse.sshfs",
+    "davfs",
+    "glusterfs",
+    "ceph",
+];
+const VIRTUAL_TYPES: [&str; 9] = [
+    "tmpfs",
+    "ramfs",
+    "proc",
+    "sysfs",
+    "devpts",
+    "cgroup2",
+    "efivarfs",
+    "securityfs",
+    "debugfs",
+];
+// Whatever is mounted here fights with @system for the same directory.
+const SYSTEM_MOUNTPOINTS: [&str; 8] = [
+    "/", "/usr", "/etc", "/var", "/home", "/root", "/boot", "/efi",
+];
+const STABLE_SPECS: [&str; 4] = ["UUID=", "PARTUUID=", "LABEL=", "PARTLABEL="];
+// Where units are enabled, relative to systemd/system.
+const ENABLEMENT_SUFFIXES: [&str; 3] = [".wants", ".requires", ".upholds"];
+const DISABLED_SUFFIX: &str = ".btrfs-migrator-disabled";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Category {
+    // A swap partition, or a swapfile outside of @system.
+    Swap,
+    // Inside @system, moves into the swap subvolume.
+    Swapfile,
+    Virtual,
+    // Another disk, found by something that does not change between boots.
+    DataDisk,
+    // A device from crypttab. Whether it unlocks is up to its crypttab entry.
+    EncryptedDisk,
+    HomeBind,
+    // Only with nofail, an unreachable server must not hold up the boot.
+    NetworkShare,
+    // Mounts one of the v1 data subvolumes where @system has its snapshot anyway, or @home anywhere.
+    LegacySubvolume,
+    // Mounts one of the v1 data subvolumes elsewhere, from now on its snapshot in @system.
+    MovedSubvolume,
+    Unknown,
+}
+
+impl Category {
+    pub fn migratable(self) -> bool {
+        self != Category::Unknown
+    }
+}
+
+pub struct Entry {
+    pub line: String,
+    pub spec: String,
+    pub mountpoint: String,
+    pub category: Category,
+    // What goes into @system/etc in place of the line.
+    rewritten: Vec<String>,
+}
+
+// The planned action for a mount of `spec`, for the user. `rewritten` is what a moved subvolume gets rewritten to.
+fn describe(category: Category, spec: &str, rewritten: &str) -> String {
+    match category {
+        Category::Swap => tr("keep, swap", &[]),
+        Category::Swapfile => tr(
+            "recreate in a swap subvolume, rewrite to: {line}",
+            &[("line", &rewritten)],
+        ),
+        Category::Virtual => tr("keep, virtual file system", &[]),
+        Category::DataDisk => tr("keep, data disk", &[]),
+        Category::EncryptedDisk => tr(
+            "keep, data disk on encrypted device {name}",
+            &[("name", &spec.trim_start_matches("/dev/mapper/"))],
+        ),
+        Category::HomeBind => tr("keep, bind mount into /home", &[]),
+        Category::NetworkShare => tr("keep, network share", &[]),
+        Category::LegacySubvolume => tr("disable, the data is part of @system now", &[]),
+        Category::MovedSubvolume => tr("rewrite to: {line}", &[("line", &rewritten)]),
+        Category::Unknown => tr("keep unchanged, cannot tell whether it still works", &[]),
+    }
+}
+
+impl Entry {
+    // The planned action, for the user.
+    pub fn describe(&self) -> String {
+        describe(self.category, &self.spec, &self.rewritten.join(" "))
+    }
+}
+
+// A crypttab entry.
+pub struct Device {
+    line: String,
+    name: String,
+    // Found by something that does not change between boots.
+    stable: bool,
+    // On the root file system, keyfiles on other devices don't move.
+    keyfile: Option<String>,
+    // Of the fstab entries on the device.
+    mountpoints: Vec<String>,
+}
+
+impl Device {
+    // The data subvolume target the keyfile is in.
+    fn moving_keyfile(&self) -> Option<&'static str> {
+        let keyfile = Path::new(self.keyfile.as_ref()?);
+        SUBVOL_TARGETS
+            .iter()
+            .map(|(_, target)| *target)
+            .find(|target| keyfile.starts_with(format!("/{target}")))
+    }
+
+    // The planned action, for the user.
+    pub fn describe(&self) -> String {
+        let action = match (self.stable, self.mountpoints.is_empty()) {
+            (false, _) => tr(
+                "keep unchanged, the device name may change between boots",
+                &[],
+            ),
+            (true, true) => tr("keep, encrypted device", &[]),
+            (true, false) => tr(
+                "keep, encrypted device for {mountpoints}",
+                &[("mountpoints", &self.mountpoints.join(", "))],
+            ),
+        };
+        match self.moving_keyfile() {
+            Some(target) => format!(
+                "{action}\n  → {}",
+                tr(
+                    "the keyfile moves into @system along with /{dir}",
+                    &[("dir", &target)]
+                )
+            ),
+            None => action,
+        }
+    }
+}
+
+// A .mount or .automount unit in systemd/system.
+pub struct Unit {
+    // The file name, e.g. mnt-data.mount.
+    name: String,
+    // What, Where, Type and Options as one line, for the user.
+    summary: String,
+    spec: String,
+    mountpoint: String,
+    category: Category,
+    // The Options= of a moved subvolume.
+    options: Option<String>,
+    // The symlinks enabling the unit, relative to systemd/system.
+    enabled_by: Vec<PathBuf>,
+}
+
+impl Unit {
+    fn automount(&self) -> bool {
+        self.name.ends_with(".automount")
+    }
+
+    // The planned action, for the user.
+    pub fn describe(&self) -> String {
+        let action = match (self.automount(), self.category) {
+            // Whatever mounts the data in the end gets rewritten.
+            (true, Category::MovedSubvolume) => tr("keep, automount", &[]),
+            (_, Category::LegacySubvolume) => tr(
+                "disable, the data is part of @system now. The unit is renamed to {name}",
+                &[("name", &format!("{}{DISABLED_SUFFIX}", self.name))],
+            ),
+            (_, category) => describe(
+                category,
+                &self.spec,
+                &format!("Options={}", self.options.as_deref().unwrap_or_default()),
+            ),
+        };
+        if self.enabled_by.is_empty() {
+            return action;
+        }
+        let enabled_by: Vec<String> = self
+            .enabled_by
+            .iter()
+            .filter_map(|link| Some(link.parent()?.display().to_string()))
+            .collect();
+        format!(
+            "{action}\n  → {}",
+            tr(
+                "enabled through {targets}",
+                &[("targets", &enabled_by.join(", "))]
+            )
+        )
+    }
+}
+
+// One line of fstab or crypttab, or one mount unit, and what happens to it.
+pub struct Action<'a> {
+    pub file: &'a str,
+    pub line: &'a str,
+    pub description: String,
+    pub migratable: bool,
+}
+
+pub struct Plan {
+    // Comments and anything we cannot parse stay as they are.
+    lines: Vec<Result<Entry, String>>,
+    devices: Vec<Result<Device, String>>,
+    units: Vec<Unit>,
+}
+
+// Spaces and friends are octal escapes, e.g. \040, as in mountinfo. Entries are matched as text, a field that does
+// not decode to UTF-8 matches nothing of ours either way.
+fn unescape(field: &str) -> String {
+    mountinfo::unescape(field).to_string_lossy().into_owned()
+}
+
+// Where the snapshot of what `options` mount is in @system, when that is a v1 data subvolume or a subvolume nested in
+// one. By name or by ID, `ids` maps the IDs of the v1 data subvolumes to their names. Nested subvolumes are only found
+// by name, their IDs are not known.
+fn legacy_subvolume(options: &[&str], ids: &[(u64, &str)]) -> Option<String> {
+    options.iter().find_map(|option| {
+        let subvol = match option.strip_prefix("subvol=") {
+            Some(subvol) => subvol.trim_matches('/'),
+            None => {
+                let id: u64 = option.strip_prefix("subvolid=")?.parse().ok()?;
+                ids.iter().find(|(other, _)| *other == id)?.1
+            }
+        };
+        let (name, nested) = subvol.split_once('/').unwrap_or((subvol, ""));
+        let (_, target) = SUBVOL_TARGETS.iter().find(|(other, _)| *other == name)?;
+        Some(match nested {
+            "" => target.to_string(),
+            nested => format!("{target}/{nested}"),
+        })
+    })
+}
+
+// subvol= and subvolid= replaced by the path of the snapshot in @system.
+fn rewrite_options(options: &[&str], target: &str) -> String {
+    let subvol = format!("subvol=/@system/{target}");
+    let mut rewritten: Vec<&str> = Vec::new();
+    for option in options {
+        if !option.starts_with("subvol=") && !option.starts_with("subvolid=") {
+            rewritten.push(option);
+        } else if !rewritten.contains(&subvol.as_str()) {
+            rewritten.push(&subvol);
+        }
+    }
+    rewritten.join(",")
+}
+
+// `line` with field number `index` replaced, keeping the whitespace between the fields.
+fn replace_field(line: &str, index: usize, value: &str) -> String {
+    let mut field = 0;
+    let mut start = None;
+    for (position, character) in line.char_indices().chain([(line.len(), ' ')]) {
+        match (character.is_whitespace(), start) {
+            (false, None) => start = Some(position),
+            (true, Some(begin)) => {
+                if field == index {
+                    return format!("{}{value}{}", &line[..begin], &line[position..]);
+                }
+                field += 1;
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    line.to_string()
+}
+
+fn classify(
+    spec: &str,
+    mountpoint: &str,
+    vfs_type: &str,
+    options: &[&str],
+    ids: &[(u64, &str)],
+    devices: &[String],
+) -> Category {
+    let mountpoint = mountpoint.trim_end_matches('/');
+    let mountpoint = if mountpoint.is_empty() {
+        "/"
+    } else {
+        mountpoint
+    };
+
+    if vfs_type == "swap" {
+        let path = Path::new(spec);
+        return if ["etc", "var"]
+            .iter()
+            .chain(SUBVOL_TARGETS.iter().map(|(_, target)| target))
+            .any(|dir| path.starts_with(Path::new("/").join(dir)))
+        {
+            Category::Swapfile
+        } else {
+            Category::Swap
+        };
+    }
+    // Mount units may leave the type to blkid.
+    if ["btrfs", "auto", ""].contains(&vfs_type)
+        && let Some(target) = legacy_subvolume(options, ids)
+    {
+        return if mountpoint.strip_prefix('/') == Some(target.as_str()) || target == "home" {
+            Category::LegacySubvolume
+        } else {
+            Category::MovedSubvolume
+        };
+    }
+    if NETWORK_TYPES.contains(&vfs_type) {
+        return if options.contains(&"nofail") {
+            Category::NetworkShare
+        } else {
+            Category::Unknown
+        };
+    }
+    if options
+        .iter()
+        .any(|option| *option == "bind" || *option == "rbind")
+        && mountpoint.starts_with("/home/")
+    {
+        return Category::HomeBind;
+    }
+    // /usr is the image, nothing can be mounted below it. The data subvolumes are in @system now and anything else
+    // mounted in their place hides them.
+    if SYSTEM_MOUNTPOINTS.contains(&mountpoint)
+        || mountpoint.starts_with("/usr/")
+        || SUBVOL_TARGETS
+            .iter()
+            .any(|(_, target)| mountpoint.strip_prefix('/') == Some(target))
+    {
+        return Category::Unknown;
+    }
+    if VIRTUAL_TYPES.contains(&vfs_type) {
+        return Category::Virtual;
+    }
+    if let Some(name) = spec.strip_prefix("/dev/mapper/")
+        && devices.iter().any(|device| device == name)
+    {
+        return Category::EncryptedDisk;
+    }
+    if stable(spec) {
+        return Category::DataDisk;
+    }
+    Category::Unknown
+}
+
+fn stable(spec: &str) -> bool {
+    STABLE_SPECS.iter().any(|prefix| spec.starts_with(prefix)) || spec.starts_with("/dev/disk/by-")
+}
+
+// `devices` are the names of the crypttab devices.
+fn parse(line: &str, ids: &[(u64, &str)], devices: &[String]) -> Result<Entry, String> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if line.trim_start().starts_with('#') || fields.len() < 3 {
+        return Err(line.to_string());
+    }
+    let spec = unescape(fields[0]);
+    let mountpoint = unescape(fields[1]);
+    let options: Vec<&str> = fields
+        .get(3)
+        .map_or(vec!["defaults"], |options| options.split(',').collect());
+    let category = classify(&spec, &mountpoint, fields[2], &options, ids, devices);
+    let rewritten = match (category, legacy_subvolume(&options, ids)) {
+        (Category::LegacySubvolume, _) => vec![
+            "# Disabled by btrfs-migrator, the data of this subvolume is part of @system now:"
+                .to_string(),
+            format!("# {line}"),
+        ],
+        (Category::MovedSubvolume, Some(target)) => {
+            vec![replace_field(line, 3, &rewrite_options(&options, &target))]
+        }
+        (Category::Swapfile, _) => vec![replace_field(
+            line,
+            0,
+            &format!("/{}/{}", swap::SUBVOLUME, swap::name(&spec)),
+        )],
+        _ => vec![line.to_string()],
+    };
+    Ok(Entry {
+        line: line.to_string(),
+        spec,
+        mountpoint,
+        category,
+        rewritten,
+    })
+}
+
+// name, device, keyfile, options. The last two are optional.
+fn parse_device(line: &str) -> Result<Device, String> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if line.trim_start().starts_with('#') || fields.len() < 2 {
+        return Err(line.to_string());
+    }
+    let keyfile = fields
+        .get(2)
+        .filter(|keyfile| !["none", "-"].contains(keyfile) && !keyfile.contains(':'))
+        .map(|keyfile| unescape(keyfile));
+    Ok(Device {
+        line: line.to_string(),
+        name: unescape(fields[0]),
+        stable: stable(fields[1]),
+        keyfile,
+        mountpoints: Vec::new(),
+    })
+}
+
+// A unified diff of a line by line rewrite, for the log. None when nothing changed.
+fn diff(path: &Path, lines: &[(&str, &[String])]) -> Option<String> {
+    let mut text = format!("--- {0}\n+++ {0}\n", path.display());
+    let mut changed = false;
+    let mut new_number = 1;
+    for (index, (old, new)) in lines.iter().enumerate() {
+        if *new != [*old] {
+            changed = true;
+            let _ = writeln!(text, "@@ -{},1 +{new_number},{} @@", index + 1, new.len());
+            let _ = writeln!(text, "-{old}");
+            for line in new.iter() {
+                let _ = writeln!(text, "+{line}");
+            }
+        }
+        new_number += new.len();
+    }
+    changed.then_some(text)
+}
+
+// Replace `path` with the new side of `lines`, should that differ from the old one.
+fn rewrite(path: &Path, lines: &[(&str, &[String])]) -> io::Result<bool> {
+    let Some(diff) = diff(path, lines) else {
+        return Ok(false);
+    };
+    let mut content = String::new();
+    for line in lines.iter().flat_map(|(_, new)| new.iter()) {
+        content.push_str(line);
+        content.push('\n');
+    }
+
+    let dir = path.parent().unwrap_or(Path::new("/"));
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = dir.join(format!(".{name}.btrfs-migrator"));
+    let mut file = File::create(&tmp)?;
+    file.write_all(content.as_bytes())?;
+    file.sync_all()?;
+    if let Ok(metadata) = fs::metadata(path) {
+        fs::set_permissions(&tmp, metadata.permissions())?;
+    }
+    fs::rename(&tmp, path)?;
+    fsync_dir(dir)?;
+    print!("{diff}");
+    Ok(true)
+}
+
+// The last value of `key` in `section` of a unit file, empty when unset.
+fn setting(content: &str, section: &str, key: &str) -> String {
+    let mut current = "";
+    let mut value = String::new();
+    for line in content.lines().map(str::trim) {
+        if let Some(name) = line
+            .strip_prefix('[')
+            .and_then(|line| line.strip_suffix(']'))
+        {
+            current = name;
+        } else if current == section
+            && let Some((name, other)) = line.split_once('=')
+            && name.trim() == key
+        {
+            value = other.trim().to_string();
+        }
+    }
+    value
+}
+
+// `content` with every `key` in `section` set to `value`, matched the way setting() does. None without such a key.
+fn replace_setting(content: &str, section: &str, key: &str, value: &str) -> Option<Vec<String>> {
+    let mut current = "";
+    let mut replaced = false;
+    let lines = content
+        .lines()
+        .map(|line| {
+            let trimmed = line.trim();
+            if let Some(name) = trimmed
+                .strip_prefix('[')
+                .and_then(|line| line.strip_suffix(']'))
+            {
+                current = name;
+            } else if current == section
+                && let Some((name, _)) = trimmed.split_once('=')
+                && name.trim() == key
+            {
+                replaced = true;
+                return format!("{key}={value}");
+            }
+            line.to_string()
+        })
+        .collect();
+    replaced.then_some(lines)
+}
+
+// The mount units in `dir`, a systemd/system. Automount units take after their mount unit, or the fstab entry in
+// `entries` they belong to.
+fn units(
+    dir: &Path,
+    ids: &[(u64, &str)],
+    devices: &[String],
+    entries: &[&Entry],
+) -> io::Result<Vec<Unit>> {
+    let read_dir = match fs::read_dir(dir) {
+        Ok(read_dir) => read_dir,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut names = Vec::new();
+    let mut links = Vec::new();
+    for entry in read_dir {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let file_type = entry.file_type()?;
+        if file_type.is_dir()
+            && ENABLEMENT_SUFFIXES
+                .iter()
+                .any(|suffix| name.ends_with(suffix))
+        {
+            for link in fs::read_dir(entry.path())? {
+                let link = link?;
+                if link.file_type()?.is_symlink() {
+                    links.push(Path::new(&name).join(link.file_name()));
+                }
+            }
+        } else if file_type.is_file() && (name.ends_with(".mount") || name.ends_with(".automount"))
+        {
+            // Masked units are symlinks to /dev/null and linked units live elsewhere, neither is ours to change.
+            names.push(name);
+        }
+    }
+    names.sort();
+    links.sort();
+    let enabled_by = |name: &str| -> Vec<PathBuf> {
+        links
+            .iter()
+            .filter(|link| link.file_name().is_some_and(|other| other == name))
+            .cloned()
+            .collect()
+    };
+
+    let mut units = Vec::new();
+    for name in names.iter().filter(|name| name.ends_with(".mount")) {
+        let content = fs::read_to_string(dir.join(name))?;
+        let spec = setting(&content, "Mount", "What");
+        let mountpoint = setting(&content, "Mount", "Where");
+        let vfs_type = setting(&content, "Mount", "Type");
+        let options = setting(&content, "Mount", "Options");
+        let summary = format!("What={spec} Where={mountpoint} Type={vfs_type} Options={options}");
+        let options: Vec<&str> = if options.is_empty() {
+            vec!["defaults"]
+        } else {
+            options.split(',').collect()
+        };
+        let category = classify(&spec, &mountpoint, &vfs_type, &options, ids, devices);
+        let options = match (category, legacy_subvolume(&options, ids)) {
+            (Category::MovedSubvolume, Some(target)) => Some(rewrite_options(&options, &target)),
+            _ => None,
+        };
+        units.push(Unit {
+            name: name.clone(),
+            summary,
+            spec,
+            mountpoint,
+            category,
+            options,
+            enabled_by: enabled_by(name),
+        });
+    }
+    for name in names.iter().filter(|name| name.ends_with(".automount")) {
+        let content = fs::read_to_string(dir.join(name))?;
+        let mountpoint = setting(&content, "Automount", "Where");
+        let mount = name.replace(".automount", ".mount");
+        let (category, spec) = units
+            .iter()
+            .find(|unit| unit.name == mount)
+            .map(|unit| (unit.category, unit.spec.clone()))
+            .or_else(|| {
+                entries
+                    .iter()
+                    .find(|entry| {
+                        entry.mountpoint.trim_end_matches('/') == mountpoint.trim_end_matches('/')
+                    })
+                    .map(|entry| (entry.category, entry.spec.clone()))
+            })
+            .unwrap_or((Category::Unknown, String::new()));
+        units.push(Unit {
+            name: name.clone(),
+            summary: format!("Where={mountpoint}"),
+            spec,
+            mountpoint,
+            category,
+            options: None,
+            enabled_by: enabled_by(name),
+        });
+    }
+    Ok(units)
+}
+
+fn read(path: &Path) -> io::Result<String> {
+    match fs::read_to_string(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+        result => result,
+    }
+}
+
+// fstab, crypttab and the mount units in `etc`, any may be missing. `ids` maps the IDs of the v1 data subvolumes to
+// their names, for subvolid=.
+pub fn plan(etc: &Path, ids: &[(u64, &str)]) -> io::Result<Plan> {
+    let mut devices: Vec<Result<Device, String>> = read(&etc.join("crypttab"))?
+        .lines()
+        .map(parse_device)
+        .collect();
+    let names: Vec<String> = devices
+        .iter()
+        .flatten()
+        .map(|device| device.name.clone())
+        .collect();
+    let lines: Vec<Result<Entry, String>> = read(&etc.join("fstab"))?
+        .lines()
+        .map(|line| parse(line, ids, &names))
+        .collect();
+    for device in devices.iter_mut().flatten() {
+        let spec = format!("/dev/mapper/{}", device.name);
+        device.mountpoints = lines
+            .iter()
+            .flatten()
+            .filter(|entry| entry.spec == spec)
+            .map(|entry| entry.mountpoint.clone())
+            .collect();
+    }
+    let entries: Vec<&Entry> = lines.iter().flatten().collect();
+    let units = units(&etc.join("systemd/system"), ids, &names, &entries)?;
+    Ok(Plan {
+        lines,
+        devices,
+        units,
+    })
+}
+
+impl Plan {
+    fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.lines.iter().flatten()
+    }
+
+    fn devices(&self) -> impl Iterator<Item = &Device> {
+        self.devices.iter().flatten()
+    }
+
+    // The v1 paths of the swapfiles for swap::migrate.
+    pub fn swapfiles(&self) -> Vec<&str> {
+        self.entries()
+            .filter(|entry| entry.category == Category::Swapfile)
+            .map(|entry| entry.spec.as_str())
+            .collect()
+    }
+
+    pub fn actions(&self) -> Vec<Action<'_>> {
+        let entries = self.entries().map(|entry| Action {
+            file: "fstab",
+            line: &entry.line,
+            description: entry.describe(),
+            migratable: entry.category.migratable(),
+        });
+        let devices = self.devices().map(|device| Action {
+            file: "crypttab",
+            line: &device.line,
+            description: device.describe(),
+            migratable: device.stable,
+        });
+        let units = self.units.iter().map(|unit| Action {
+            file: &unit.name,
+            line: &unit.summary,
+            description: unit.describe(),
+            migratable: unit.category.migratable(),
+        });
+        entries.chain(devices).chain(units).collect()
+    }
+
+    // Replaces the verbatim copies in the new `etc`.
+    // `swapfiles` are those swap::migrate recreated.
+    pub fn write(&self, etc: &Path, swapfiles: &[&str]) -> io::Result<()> {
+        let recreated = |entry: &Entry| swapfiles.contains(&entry.spec.as_str());
+        let lines: Vec<(&str, &[String])> = self
+            .lines
+            .iter()
+            .map(|line| match line {
+                Ok(entry) if entry.category == Category::Swapfile && !recreated(entry) => {
+                    (entry.line.as_str(), slice::from_ref(&entry.line))
+                }
+                Ok(entry) => (entry.line.as_str(), entry.rewritten.as_slice()),
+                Err(line) => (line.as_str(), slice::from_ref(line)),
+            })
+            .collect();
+        rewrite(&etc.join("fstab"), &lines)?;
+
+        for entry in self.entries() {
+            let fields: [(&str, &dyn Display); 1] = [("PATH", &entry.mountpoint)];
+            match entry.category {
+                Category::LegacySubvolume => journal::warning(
+                    &format!(
+                        "Disabled the fstab entry for {} ({}), its data is part of @system now",
+                        entry.mountpoint, entry.spec
+                    ),
+                    &fields,
+                ),
+                Category::MovedSubvolume => journal::warning(
+                    &format!(
+                        "Rewrote the fstab entry for {} to mount the snapshot of its subvolume in @system",
+                        entry.mountpoint
+                    ),
+                    &fields,
+                ),
+                Category::Unknown => journal::warning(
+                    &format!(
+                        "Kept the fstab entry for {} ({}) unchanged, it may not work with the new layout",
+                        entry.mountpoint, entry.spec
+                    ),
+                    &fields,
+                ),
+                Category::Swapfile if recreated(entry) => println!(
+                    "Pointed the fstab entry for {} at the swap subvolume",
+                    entry.spec
+                ),
+                Category::Swapfile => println!(
+                    "Kept the fstab entry for {}, there is no new swapfile to point it at",
+                    entry.spec
+                ),
+                _ => println!("Kept the fstab entry for {}", entry.mountpoint),
+            }
+        }
+
+        for device in self.devices() {
+            let fields: [(&str, &dyn Display); 1] = [("PATH", &device.name)];
+            if !device.stable {
+                journal::warning(
+                    &format!(
+                        "Kept the crypttab entry for {} unchanged, its device name may change between boots",
+                        device.name
+                    ),
+                    &fields,
+                );
+            }
+            if let (Some(keyfile), Some(target)) = (&device.keyfile, device.moving_keyfile()) {
+                journal::warning(
+                    &format!(
+                        "The keyfile {keyfile} for {} moved into @system along with /{target}. \
+                         Make sure it still unlocks.",
+                        device.name
+                    ),
+                    &fields,
+                );
+            }
+        }
+
+        let dir = etc.join("systemd/system");
+        for unit in &self.units {
+            let path = dir.join(&unit.name);
+            let fields: [(&str, &dyn Display); 1] = [("PATH", &path.display())];
+            match (unit.automount(), unit.category, &unit.options) {
+                (_, Category::LegacySubvolume, _) => {
+                    for link in &unit.enabled_by {
+                        let link = dir.join(link);
+                        match fs::remove_file(&link) {
+                            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                            _ => fsync_dir(link.parent().unwrap_or(&dir))?,
+                        }
+                    }
+                    let disabled = dir.join(format!("{}{DISABLED_SUFFIX}", unit.name));
+                    fs::rename(&path, &disabled)?;
+                    fsync_dir(&dir)?;
+                    journal::warning(
+                        &format!(
+                            "Disabled {} for {}, its data is part of @system now. The unit is at {disabled:?}.",
+                            unit.name, unit.mountpoint
+                        ),
+                        &fields,
+                    );
+                }
+                (false, Category::MovedSubvolume, Some(options)) => {
+                    let content = fs::read_to_string(&path)?;
+                    // units() only takes Options= from where this finds it, so not finding it is a bug.
+                    let rewritten = replace_setting(&content, "Mount", "Options", options)
+                        .ok_or_else(|| {
+                            io::Error::other(format!(
+                                "No Options= in the [Mount] section of {path:?}"
+                            ))
+                        })?;
+                    let lines: Vec<(&str, &[String])> = content
+                        .lines()
+                        .zip(rewritten.iter().map(slice::from_ref))
+                        .collect();
+                    if rewrite(&path, &lines)? {
+                        journal::warning(
+                            &format!(
+                                "Rewrote {} to mount the snapshot of its subvolume in @system",
+                                unit.name
+                            ),
+                            &fields,
+                        );
+                    }
+                }
+                (_, Category::Unknown, _) => journal::warning(
+                    &format!(
+                        "Kept {} for {} unchanged, it may not work with the new layout",
+                        unit.name, unit.mountpoint
+                    ),
+                    &fields,
+                ),
+                _ => println!("Kept {}", unit.name),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, os::unix::fs::symlink, process};
+
+    use super::*;
+
+    // An empty directory of its own for each test.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("btrfs-migrator-{}-{name}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn category(line: &str) -> Category {
+        parse(line, &[], &[]).unwrap().category
+    }
+
+    #[test]
+    fn comments_and_blank_lines_stay() {
+        for line in ["# /etc/fstab", "  # indented", "", "   ", "UUID=1234 /data"] {
+            assert_eq!(parse(line, &[], &[]).err().as_deref(), Some(line));
+        }
+    }
+
+    #[test]
+    fn octal_escapes() {
+        assert_eq!(unescape("/mnt/my\\040disk"), "/mnt/my disk");
+        assert_eq!(unescape("tab\\011and\\134"), "tab\tand\\");
+        // Not an escape, kept as is.
+        assert_eq!(unescape("C:\\x"), "C:\\x");
+        assert_eq!(unescape("end\\"), "end\\");
+        assert_eq!(unescape("short\\04"), "short\\04");
+        assert_eq!(unescape("sign\\+12"), "sign\\+12");
+        // UTF-8 is escaped byte by byte.
+        assert_eq!(unescape("/mnt/b\\303\\244r"), "/mnt/bär");
+
+        let line = "LABEL=My\\040Data /mnt/my\\040data ext4 defaults 0 2";
+        let entry = parse(line, &[], &[]).unwrap();
+        assert_eq!(entry.spec, "LABEL=My Data");
+        assert_eq!(entry.mountpoint, "/mnt/my data");
+        assert_eq!(entry.category, Category::DataDisk);
+        assert_eq!(entry.rewritten, [line]);
+    }
+
+    #[test]
+    fn defaults_only_options() {
+        assert_eq!(
+            category("UUID=1234 /data ext4 defaults 0 2"),
+            Category::DataDisk
+        );
+        // Options may be left out entirely.
+        assert_eq!(category("UUID=1234 /data ext4"), Category::DataDisk);
+        assert_eq!(
+            category("server:/export /mnt/nfs nfs defaults 0 0"),
+            Category::Unknown
+        );
+    }
+
+    #[test]
+    fn categories() {
+        assert_eq!(category("tmpfs /tmp tmpfs defaults 0 0"), Category::Virtual);
+        assert_eq!(
+            category("/dev/sdb1 /data ext4 defaults 0 2"),
+            Category::Unknown
+        );
+        assert_eq!(
+            category("/dev/disk/by-id/ata-disk /data ext4 defaults 0 2"),
+            Category::DataDisk
+        );
+        assert_eq!(category("UUID=1234 none swap sw 0 0"), Category::Swap);
+        assert_eq!(
+            category("server:/export /mnt/nfs nfs4 nofail 0 0"),
+            Category::NetworkShare
+        );
+        assert_eq!(
+            category("/data/alice /home/alice/data none bind 0 0"),
+            Category::HomeBind
+        );
+        // Fights with @system.
+        assert_eq!(
+            category("UUID=1234 /var ext4 defaults 0 2"),
+            Category::Unknown
+        );
+        assert_eq!(
+            category("UUID=1234 /var/ ext4 defaults 0 2"),
+            Category::Unknown
+        );
+        assert_eq!(
+            category("UUID=1234 / btrfs defaults 0 0"),
+            Category::Unknown
+        );
+        assert_eq!(
+            category("UUID=1234 /usr/local ext4 defaults 0 2"),
+            Category::Unknown
+        );
+        assert_eq!(
+            category("UUID=1234 /var/lib/docker ext4 defaults 0 2"),
+            Category::Unknown
+        );
+    }
+
+    #[test]
+    fn replace_field_keeps_whitespace() {
+        let line = "UUID=1234\t/data   btrfs  defaults 0 0";
+        assert_eq!(
+            replace_field(line, 3, "compress=zstd"),
+            "UUID=1234\t/data   btrfs  compress=zstd 0 0"
+        );
+        assert_eq!(
+            replace_field(line, 0, "LABEL=x"),
+            "LABEL=x\t/data   btrfs  defaults 0 0"
+        );
+        // The last field, without anything after it.
+        assert_eq!(replace_field("a b c", 2, "d"), "a b d");
+        assert_eq!(replace_field("  a b", 0, "c"), "  c b");
+        assert_eq!(replace_field(line, 9, "x"), line);
+    }
+
+    #[test]
+    fn no_trailing_newline() {
+        let etc = scratch("no-trailing-newline");
+        fs::write(
+            etc.join("fstab"),
+            "# data\n\nUUID=1234 /data ext4 defaults 0 2\ntmpfs /scratch tmpfs defaults",
+        )
+        .unwrap();
+        let plan = plan(&etc, &[]).unwrap();
+        let actions = plan.actions();
+        assert_eq!(actions.len(), 2);
+        assert_eq!(actions[1].line, "tmpfs /scratch tmpfs defaults");
+        assert!(actions.iter().all(|action| action.migratable));
+        fs::remove_dir_all(&etc).unwrap();
+    }
+
+    const IDS: [(u64, &str); 2] = [(257, "@home"), (258, "@containers")];
+
+    fn category_with_ids(line: &str) -> Category {
+        parse(line, &IDS, &[]).unwrap().category
+    }
+
+    #[test]
+    fn subvol_and_subvolid() {
+        let target = |options: &[&str]| legacy_subvolume(options, &IDS);
+        assert_eq!(target(&["subvol=@home"]).as_deref(), Some("home"));
+        assert_eq!(target(&["subvol=/@home/"]).as_deref(), Some("home"));
+        assert_eq!(
+            target(&["noatime", "subvolid=258"]).as_deref(),
+            Some("var/lib/containers")
+        );
+        // Nested in a data subvolume.
+        assert_eq!(
+            target(&["subvol=@home/alice"]).as_deref(),
+            Some("home/alice")
+        );
+        assert_eq!(
+            target(&["subvol=/@containers/storage/volumes"]).as_deref(),
+            Some("var/lib/containers/storage/volumes")
+        );
+        // Not a data subvolume, or an ID we do not know.
+        assert_eq!(legacy_subvolume(&["subvol=@homes"], &IDS), None);
+        assert_eq!(legacy_subvolume(&["subvol=@homes/alice"], &IDS), None);
+        assert_eq!(legacy_subvolume(&["subvolid=999"], &IDS), None);
+        assert_eq!(legacy_subvolume(&["subvolid=@home"], &IDS), None);
+        assert_eq!(legacy_subvolume(&["defaults"], &IDS), None);
+    }
+
+    #[test]
+    fn rewritten_options() {
+        assert_eq!(
+            rewrite_options(
+                &["noatime", "subvol=@containers", "compress=zstd"],
+                "var/lib/containers"
+            ),
+            "noatime,subvol=/@system/var/lib/containers,compress=zstd"
+        );
+        // Both name the same subvolume, one of them is enough.
+        assert_eq!(
+            rewrite_options(
+                &["subvol=@containers", "subvolid=258"],
+                "var/lib/containers"
+            ),
+            "subvol=/@system/var/lib/containers"
+        );
+    }
+
+    #[test]
+    fn legacy_and_moved_subvolumes() {
+        let line = "UUID=1234 /home btrfs subvol=@home,compress=zstd 0 0";
+        let entry = parse(line, &IDS, &[]).unwrap();
+        assert_eq!(entry.category, Category::LegacySubvolume);
+        assert_eq!(entry.rewritten.len(), 2);
+        assert!(entry.rewritten.iter().all(|line| line.starts_with('#')));
+        assert_eq!(entry.rewritten[1], format!("# {line}"));
+
+        let entry = parse(
+            "UUID=1234  /srv/containers  auto  subvolid=258  0 0",
+            &IDS,
+            &[],
+        )
+        .unwrap();
+        assert_eq!(entry.category, Category::MovedSubvolume);
+        assert_eq!(
+            entry.rewritten,
+            ["UUID=1234  /srv/containers  auto  subvol=/@system/var/lib/containers  0 0"]
+        );
+
+        // Pointed at the snapshot of a nested subvolume, which run_v3 leaves a subvolume.
+        let entry = parse("UUID=1234 /vms btrfs subvol=@home/alice/vms 0 0", &IDS, &[]).unwrap();
+        assert_eq!(entry.category, Category::MovedSubvolume);
+        assert_eq!(
+            entry.rewritten,
+            ["UUID=1234 /vms btrfs subvol=/@system/home/alice/vms 0 0"]
+        );
+        // Where @system has it anyway.
+        assert_eq!(
+            category_with_ids("UUID=1234 /home/alice btrfs subvol=@home/alice 0 0"),
+            Category::LegacySubvolume
+        );
+        // run_v3 turns the snapshot of @home into a directory, wherever it was mounted.
+        assert_eq!(
+            category_with_ids("UUID=1234 /srv/home btrfs subvolid=257 0 0"),
+            Category::LegacySubvolume
+        );
+
+        // Only btrfs has subvolumes.
+        assert_eq!(
+            parse("UUID=1234 /srv ext4 subvol=@home 0 0", &IDS, &[])
+                .unwrap()
+                .category,
+            Category::DataDisk
+        );
+    }
+
+    #[test]
+    fn rewrite_without_trailing_newline() {
+        let etc = scratch("rewrite-without-trailing-newline");
+        let fstab = etc.join("fstab");
+        fs::write(
+            &fstab,
+            "# data\nUUID=1234 /srv/c btrfs subvol=@containers 0 0",
+        )
+        .unwrap();
+        plan(&etc, &IDS).unwrap().write(&etc, &[]).unwrap();
+        assert_eq!(
+            fs::read_to_string(&fstab).unwrap(),
+            "# data\nUUID=1234 /srv/c btrfs subvol=/@system/var/lib/containers 0 0\n"
+        );
+        // Points at @system now, nothing left to change.
+        let plan = plan(&etc, &IDS).unwrap();
+        assert_eq!(plan.actions()[0].description, "keep, data disk");
+        assert_eq!(diff(&fstab, &[("same", &["same".to_string()])]), None);
+        fs::remove_dir_all(&etc).unwrap();
+    }
+
+    #[test]
+    fn swapfiles_follow_when_recreated() {
+        let etc = scratch("swapfiles-follow-when-recreated");
+        let fstab = etc.join("fstab");
+        fs::write(
+            &fstab,
+            "/var/swapfile none swap defaults 0 0\n/var/swap/file none swap defaults 0 0\n",
+        )
+        .unwrap();
+        let plan = plan(&etc, &IDS).unwrap();
+        assert_eq!(plan.swapfiles(), ["/var/swapfile", "/var/swap/file"]);
+        plan.write(&etc, &["/var/swap/file"]).unwrap();
+        assert_eq!(
+            fs::read_to_string(&fstab).unwrap(),
+            "/var/swapfile none swap defaults 0 0\n/swap/var-swap-file none swap defaults 0 0\n"
+        );
+        fs::remove_dir_all(&etc).unwrap();
+    }
+
+    #[test]
+    fn crypttab() {
+        for line in ["# crypttab", "", "lonely"] {
+            assert_eq!(parse_device(line).err().as_deref(), Some(line));
+        }
+
+        let device = parse_device("data UUID=1234 none luks,discard").unwrap();
+        assert_eq!(device.name, "data");
+        assert!(device.stable);
+        assert_eq!(device.keyfile, None);
+        assert_eq!(parse_device("data /dev/sdb2").unwrap().keyfile, None);
+        assert!(!parse_device("data /dev/sdb2 - luks").unwrap().stable);
+
+        let device = parse_device("data\tPARTUUID=1234\t/root/data\\040key luks").unwrap();
+        assert_eq!(device.keyfile.as_deref(), Some("/root/data key"));
+        assert_eq!(device.moving_keyfile(), Some("root"));
+        let device = parse_device("data UUID=1234 /etc/data.key luks").unwrap();
+        assert_eq!(device.moving_keyfile(), None);
+        // On another device.
+        let device = parse_device("data UUID=1234 /key:UUID=5678 luks").unwrap();
+        assert_eq!(device.keyfile, None);
+    }
+
+    #[test]
+    fn encrypted_disks() {
+        let devices = ["data".to_string()];
+        let entry = parse("/dev/mapper/data /data ext4 defaults 0 2", &[], &devices).unwrap();
+        assert_eq!(entry.category, Category::EncryptedDisk);
+        assert_eq!(
+            parse("/dev/mapper/other /data ext4 defaults 0 2", &[], &devices)
+                .unwrap()
+                .category,
+            Category::Unknown
+        );
+
+        let etc = scratch("encrypted-disks");
+        fs::write(
+            etc.join("crypttab"),
+            "data UUID=1234 /root/data.key\nswap /dev/sda3 /dev/urandom swap\n",
+        )
+        .unwrap();
+        fs::write(
+            etc.join("fstab"),
+            "/dev/mapper/data /data ext4 defaults 0 2\n",
+        )
+        .unwrap();
+        let plan = plan(&etc, &[]).unwrap();
+        let devices: Vec<&Device> = plan.devices().collect();
+        assert_eq!(devices[0].mountpoints, ["/data"]);
+        assert!(devices[1].mountpoints.is_empty());
+        let actions = plan.actions();
+        assert_eq!(actions[1].file, "crypttab");
+        assert!(actions[1].migratable);
+        assert!(actions[1].description.contains("/data"));
+        assert!(!actions[2].migratable);
+        fs::remove_dir_all(&etc).unwrap();
+    }
+
+    const UNIT: &str = "[Unit]\nOptions=ignored\n\n\
+                        [Mount]\nWhat=UUID=1234\nWhere=/srv/c\nType = btrfs\n  Options = noatime,subvol=@containers\n";
+
+    #[test]
+    fn unit_settings() {
+        assert_eq!(setting(UNIT, "Mount", "Type"), "btrfs");
+        assert_eq!(
+            setting(UNIT, "Mount", "Options"),
+            "noatime,subvol=@containers"
+        );
+        assert_eq!(setting(UNIT, "Unit", "Options"), "ignored");
+        assert_eq!(setting(UNIT, "Mount", "Missing"), "");
+
+        let lines = replace_setting(
+            UNIT,
+            "Mount",
+            "Options",
+            "noatime,subvol=/@system/var/lib/containers",
+        )
+        .unwrap();
+        assert_eq!(lines[1], "Options=ignored");
+        assert_eq!(
+            lines[7],
+            "Options=noatime,subvol=/@system/var/lib/containers"
+        );
+        assert_eq!(lines.len(), UNIT.lines().count());
+        assert_eq!(
+            replace_setting("[Mount]\nWhat=x", "Mount", "Options", "y"),
+            None
+        );
+        assert_eq!(
+            replace_setting("[Unit]\nOptions=x", "Mount", "Options", "y"),
+            None
+        );
+    }
+
+    #[test]
+    fn mount_units() {
+        let etc = scratch("mount-units");
+        let dir = etc.join("systemd/system");
+        fs::create_dir_all(dir.join("local-fs.target.wants")).unwrap();
+        fs::write(dir.join("srv-c.mount"), UNIT).unwrap();
+        fs::write(dir.join("srv-c.automount"), "[Automount]\nWhere=/srv/c\n").unwrap();
+        fs::write(
+            dir.join("home.mount"),
+            "[Mount]\nWhat=UUID=1234\nWhere=/home\nOptions=subvol=@home\n",
+        )
+        .unwrap();
+        symlink(
+            "../home.mount",
+            dir.join("local-fs.target.wants/home.mount"),
+        )
+        .unwrap();
+        symlink("/dev/null", dir.join("masked.mount")).unwrap();
+
+        let plan = plan(&etc, &IDS).unwrap();
+        let units: Vec<(&str, Category)> = plan
+            .units
+            .iter()
+            .map(|unit| (unit.name.as_str(), unit.category))
+            .collect();
+        assert_eq!(
+            units,
+            [
+                ("home.mount", Category::LegacySubvolume),
+                ("srv-c.mount", Category::MovedSubvolume),
+                ("srv-c.automount", Category::MovedSubvolume),
+            ]
+        );
+        assert_eq!(
+            plan.units[0].enabled_by,
+            [Path::new("local-fs.target.wants/home.mount")]
+        );
+
+        plan.write(&etc, &[]).unwrap();
+        assert!(!dir.join("home.mount").exists());
+        assert!(dir.join(format!("home.mount{DISABLED_SUFFIX}")).exists());
+        assert!(!dir.join("local-fs.target.wants/home.mount").exists());
+        let content = fs::read_to_string(dir.join("srv-c.mount")).unwrap();
+        assert_eq!(
+            setting(&content, "Mount", "Options"),
+            "noatime,subvol=/@system/var/lib/containers"
+        );
+        assert_eq!(setting(&content, "Unit", "Options"), "ignored");
+        assert_eq!(
+            fs::read_to_string(dir.join("srv-c.automount")).unwrap(),
+            "[Automount]\nWhere=/srv/c\n"
+        );
+        fs::remove_dir_all(&etc).unwrap();
+    }
+}
@@ -0,0 +1,243 @@
+// SPDX-License-Identifier: GPL-2.0-only OR GPL-3.0-only OR LicenseRef-KDE-Accepted-GPL
+// SPDX-FileCopyrightText: None
+
+// Where to get help when the migration fails. Every class of failure has a diagnostic code, which is also the anchor
+// of its section on the troubleshooting page. The URL goes on the console as QR code, a phone is usually the only
+// other device around when the boot is stuck.
+
+use std::{error::Error, fmt, io, iter};
+
+use crate::i18n::tr;
+
+const TROUBLESHOOTING: &str = "https://community.kde.org/KDE_Linux/RootFSv2/Troubleshooting";
+
+#[derive(Clone, Copy)]
+pub enum Class {
+    // Something is mounted or in use where we need to rename or delete.
+    Busy,
+    // Stopped by a signal at a safe point.
+    Cancelled,
+    // The user or the policy declined because of fstab entries.
+    Fstab,
+    // The kernel command line policy does not allow the migration.
+    Policy,
+    NoSpace,
+    // Leftovers of an interrupted run that we cannot tell apart safely.
+    Inconsistent,
+    NoLegacyRoot,
+    // The kernel refused to mount something we need to read from.
+    Mount,
+    Copy,
+    Btrfs,
+    Io,
+    Unknown,
+}
+
+impl Class {
+    pub fn code(self) -> &'static str {
+        match self {
+            Class::Busy => "MIG-BUSY",
+            Class::Cancelled => "MIG-CANCELLED",
+            Class::Fstab => "MIG-FSTAB",
+            Class::Policy => "MIG-POLICY",
+            Class::NoSpace => "MIG-NOSPC",
+            Class::Inconsistent => "MIG-STATE",
+            Class::NoLegacyRoot => "MIG-NOV1",
+            Class::Mount => "MIG-MOUNT",
+            Class::Copy => "MIG-COPY",
+            Class::Btrfs => "MIG-BTRFS",
+            Class::Io => "MIG-IO",
+            Class::Unknown => "MIG-UNKNOWN",
+        }
+    }
+
+    pub fn url(self) -> String {
+        format!("{TROUBLESHOOTING}#{}", self.code())
+    }
+
+    pub fn error(self, message: impl Into<String>) -> Box<dyn Error> {
+        Box::new(Failure {
+            class: self,
+            message: message.into(),
+            source: None,
+        })
+    }
+
+    // Like error(), for a `message` that describes `source` already. The source stays around, its errno is not lost.
+    pub fn caused_by(
+        self,
+        message: impl Into<String>,
+        source: impl Into<Box<dyn Error>>,
+    ) -> Box<dyn Error> {
+        Box::new(Failure {
+            class: self,
+            message: message.into(),
+            source: Some(source.into()),
+        })
+    }
+}
+
+// An error that knows what class it belongs to. Everything else gets classified by its errno.
+pub struct Failure {
+    class: Class,
+    message: String,
+    source: Option<Box<dyn Error>>,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+// Same as a plain string error, which is what main() prints on the way out.
+impl fmt::Debug for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.message)
+    }
+}
+
+impl Error for Failure {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.source.as_deref()
+    }
+}
+
+// What we were doing when `source` happened. Unlike a message formatted from it, the source stays classifiable.
+pub struct Contextual {
+    message: String,
+    source: Box<dyn Error>,
+}
+
+impl fmt::Display for Contextual {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.message, self.source)
+    }
+}
+
+impl fmt::Debug for Contextual {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.to_string())
+    }
+}
+
+impl Error for Contextual {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+pub trait Context<T> {
+    fn context<M: Into<String>>(self, message: impl FnOnce() -> M) -> Result<T, Box<dyn Error>>;
+}
+
+impl<T, E: Into<Box<dyn Error>>> Context<T> for Result<T, E> {
+    fn context<M: Into<String>>(self, message: impl FnOnce() -> M) -> Result<T, Box<dyn Error>> {
+        self.map_err(|source| {
+            Box::new(Contextual {
+                message: message().into(),
+                source: source.into(),
+            }) as Box<dyn Error>
+        })
+    }
+}
+
+fn classify_errno(errno: i32) -> Option<Class> {
+    match errno {
+        libc::ENOSPC | libc::EDQUOT => Some(Class::NoSpace),
+        libc::EBUSY => Some(Class::Busy),
+        _ => None,
+    }
+}
+
+// `error` and what caused it, outermost first.
+fn chain<'a>(error: &'a (dyn Error + 'static)) -> impl Iterator<Item = &'a (dyn Error + 'static)> {
+    iter::successors(Some(error), |&error| error.source())
+}
+
+// The outermost error that knows its class, or failing that its errno, decides.
+pub fn classify(error: &(dyn Error + 'static)) -> Class {
+    chain(error)
+        .find_map(|error| {
+            if let Some(failure) = error.downcast_ref::<Failure>() {
+                return Some(failure.class);
+            }
+            if let Some(error) = error.downcast_ref::<io::Error>() {
+                return Some(
+                    error
+                        .raw_os_error()
+                        .and_then(classify_errno)
+                        .unwrap_or(Class::Io),
+                );
+            }
+            if let Some(error) = error.downcast_ref::<libbtrfsutil::Error>() {
+                return Some(classify_errno(error.errno()).unwrap_or(Class::Btrfs));
+            }
+            None
+        })
+        .unwrap_or(Class::Unknown)
+}
+
+// For the journal, from the outermost error that has one.
+pub fn errno(error: &(dyn Error + 'static)) -> Option<i32> {
+    chain(error).find_map(|error| {
+        error
+            .downcast_ref::<io::Error>()
+            .and_then(io::Error::raw_os_error)
+            .or_else(|| {
+                error
+                    .downcast_ref::<libbtrfsutil::Error>()
+                    .map(libbtrfsutil::Error::errno)
+            })
+    })
+}
+
+// For the console, after plymouth is gone.
+pub fn show(error: &(dyn Error + 'static)) {
+    let class = classify(error);
+    let url = class.url();
+    eprintln!();
+    let _ = qr2term::print_qr(&url);
+    eprintln!(
+        "{}",
+        tr("Diagnostic code: {code}", &[("code", &class.code())])
+    );
+    eprintln!("{}", tr("Help: {url}", &[("url", &url)]));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enospc() -> Result<(), Box<dyn Error>> {
+        Err(io::Error::from_raw_os_error(libc::ENOSPC)).context(|| "Failed to copy")
+    }
+
+    #[test]
+    fn classified_through_context() {
+        let error = enospc().context(|| "Failed to migrate").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Failed to migrate: Failed to copy: {}",
+                io::Error::from_raw_os_error(libc::ENOSPC)
+            )
+        );
+        assert_eq!(classify(error.as_ref()).code(), "MIG-NOSPC");
+        assert_eq!(errno(error.as_ref()), Some(libc::ENOSPC));
+    }
+
+    #[test]
+    fn class_wins_over_errno() {
+        let error = Class::Busy.caused_by(
+            "Could not unmount",
+            io::Error::from_raw_os_error(libc::EROFS),
+        );
+        assert_eq!(classify(error.as_ref()).code(), "MIG-BUSY");
+        assert_eq!(errno(error.as_ref()), Some(libc::EROFS));
+
+        let error: Box<dyn Error> = "no errno".into();
+        assert_eq!(classify(error.as_ref()).code(), "MIG-UNKNOWN");
+        assert_eq!(errno(error.as_ref()), None);
+    }
+}
@@ -0,0 +1,123 @@
+// SPDX-License-Identifier: GPL-2.0-only OR GPL-3.0-only OR LicenseRef-KDE-Accepted-GPL
+// SPDX-FileCopyrightText: None
+
+// Translations for what the user gets to see during the migration, from the kde-linux catalog in po/ that the
+// initrd carries as .mo files. The initrd has no locale data for glibc's gettext to work with, so we read the catalog
+// ourselves.
+//
+// Strings are extracted by Messages.sh with xgettext's Rust parser, so calls have to read tr("…", …) or
+// trn("…", "…", …) with plain string literals, .gitlab-ci/scripts/check-messages.py makes sure they do. Placeholders are {name} and filled from `args`.
+
+use std::{
+    env,
+    fmt::Display,
+    fs::{self, File},
+    path::Path,
+    sync::OnceLock,
+};
+
+use gettext::Catalog;
+
+const LOCALE_DIR: &str = "/usr/share/locale";
+const DOMAIN: &str = "kde-linux";
+
+static CATALOG: OnceLock<Catalog> = OnceLock::new();
+
+// The value of `key` in a locale.conf style file.
+fn read_var(file: &Path, key: &str) -> Option<String> {
+    fs::read_to_string(file).ok()?.lines().find_map(|line| {
+        let value = line.trim().strip_prefix(key)?.strip_prefix('=')?;
+        Some(value.trim_matches('"').to_string())
+    })
+}
+
+// C and POSIX are what an initrd defaults to and say nothing about the user.
+fn meaningful(lang: Option<String>) -> Option<String> {
+    lang.filter(|lang| {
+        !lang.is_empty() && !lang.starts_with("C.") && lang != "C" && lang != "POSIX"
+    })
+}
+
+// LANGUAGE first as it is the user's ordered preference, then LANG. Either from our environment, which systemd fills
+// from locale.LANG= on the kernel command line, or from the installed system's locale.conf.
+fn languages(root: &Path) -> Vec<String> {
+    let environment = (env::var("LANG").ok(), env::var("LANGUAGE").ok());
+    let confs = ["@system/etc/locale.conf", "@etc-overlay/upper/locale.conf"].map(|conf| {
+        let conf = root.join(conf);
+        (read_var(&conf, "LANG"), read_var(&conf, "LANGUAGE"))
+    });
+
+    for (lang, language) in [environment].into_iter().chain(confs) {
+        let lang = meaningful(lang);
+        let language = language.filter(|language| !language.is_empty());
+        if lang.is_none() && language.is_none() {
+            continue;
+        }
+        let mut languages: Vec<String> = language
+            .iter()
+            .flat_map(|language| language.split(':'))
+            .map(str::to_string)
+            .collect();
+        languages.extend(lang);
+        return languages;
+    }
+    Vec::new()
+}
+
+// de_DE.UTF-8@euro → de_DE@euro, de_DE, de@euro, de. Same fallback order as gettext.
+fn candidates(language: &str) -> Vec<String> {
+    let (language, modifier) = match language.split_once('@') {
+        Some((language, modifier)) => (language, Some(modifier)),
+        None => (language, None),
+    };
+    let language = language.split('.').next().unwrap_or(language);
+    let base = language.split('_').next().unwrap_or(language);
+    let mut candidates = Vec::new();
+    for name in [language, base] {
+        if let Some(modifier) = modifier {
+            candidates.push(format!("{name}@{modifier}"));
+        }
+        candidates.push(name.to_string());
+    }
+    candidates.dedup();
+    candidates
+}
+
+pub fn init(root: &Path) {
+    let catalog = languages(root)
+        .iter()
+        .flat_map(|language| candidates(language))
+        .find_map(|name| {
+            let path = format!("{LOCALE_DIR}/{name}/LC_MESSAGES/{DOMAIN}.mo");
+            let catalog = Catalog::parse(File::open(&path).ok()?)
+                .inspect_err(|e| eprintln!("Failed to load translations {path}: {e}"))
+                .ok()?;
+            println!("Using translations from {path}");
+            Some(catalog)
+        });
+    let _ = CATALOG.set(catalog.unwrap_or_else(Catalog::empty));
+}
+
+fn fill(text: &str, args: &[(&str, &dyn Display)]) -> String {
+    let mut text = text.to_string();
+    for (name, value) in args {
+        text = text.replace(&format!("{{{name}}}"), &value.to_string());
+    }
+    text
+}
+
+pub fn tr(msgid: &str, args: &[(&str, &dyn Display)]) -> String {
+    let text = CATALOG
+        .get()
+        .map_or(msgid, |catalog| catalog.gettext(msgid));
+    fill(text, args)
+}
+
+pub fn trn(msgid: &str, plural: &str, n: u64, args: &[(&str, &dyn Display)]) -> String {
+    let text = match CATALOG.get() {
+        Some(catalog) => catalog.ngettext(msgid, plural, n),
+        None if n == 1 => msgid,
+        None => plural,
+    };
+    fill(text, args)
+}
@@ -0,0 +1,192 @@
+// SPDX-License-Identifier: GPL-2.0-only OR GPL-3.0-only OR LicenseRef-KDE-Accepted-GPL
+// SPDX-FileCopyrightText: None
+
+// Migration events for the journal, so they outlive the initrd and can be found with `journalctl MESSAGE_ID=…`. The
+// IDs are documented in /usr/lib/systemd/catalog/btrfs-migrator.catalog. Each event also gets one line on the
+// console, the free-form chatter around it stays console only. The same events make up the migration report and the
+// --output=jsonl stream.
+//
+// Speaks the native protocol, see systemd.journal-fields(7) and
+// https://systemd.io/JOURNAL_NATIVE_PROTOCOL/. Without journald, e.g. when run by hand, only the console line remains.
+
+use std::{
+    error::Error, fmt::Display, io, os::unix::net::UnixDatagram, sync::Mutex, time::Instant,
+};
+
+use crate::{events, help, report};
+
+const SOCKET: &str = "/run/systemd/journal/socket";
+
+pub const STARTED: &str = "d347e44acd0643f7bc5b1cc9e19ab2bd";
+pub const PHASE_FINISHED: &str = "25932edc838f4206aefd5e20c7d61953";
+pub const FINISHED: &str = "a7fa2c848b5a463fb661cd62430d6908";
+pub const SKIPPED: &str = "de9940b4376c4ff1a6984cb620704f87";
+pub const WARNING: &str = "9bd905a7cbd84686a228740f462ee1a4";
+pub const FAILED: &str = "1c5e3375c69e4190ac4a923e47f859af";
+
+// syslog priorities
+const ERR: u8 = 3;
+const WARN: u8 = 4;
+const INFO: u8 = 6;
+
+struct State {
+    // Attached to every event, e.g. the layout versions.
+    context: Vec<(&'static str, String)>,
+    // The phase we are in, so a failure can say where it happened.
+    phase: Option<String>,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    context: Vec::new(),
+    phase: None,
+});
+
+fn serialize(fields: &[(&str, String)]) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    for (key, value) in fields {
+        buffer.extend_from_slice(key.as_bytes());
+        if value.contains('\n') {
+            // Multi-line values are length prefixed instead of terminated by the newline.
+            buffer.push(b'\n');
+            buffer.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            buffer.push(b'=');
+        }
+        buffer.extend_from_slice(value.as_bytes());
+        buffer.push(b'\n');
+    }
+    buffer
+}
+
+fn send(fields: &[(&str, String)]) -> io::Result<()> {
+    UnixDatagram::unbound()?.send_to(&serialize(fields), SOCKET)?;
+    Ok(())
+}
+
+// The value of `key` among `fields`, as the report wants it.
+fn field(fields: &[(&str, &dyn Display)], key: &str) -> Option<String> {
+    fields
+        .iter()
+        .find(|(k, _)| *k == key)
+        .map(|(_, value)| value.to_string())
+}
+
+fn log(message_id: &str, priority: u8, message: &str, fields: &[(&str, &dyn Display)]) {
+    if priority <= WARN {
+        eprintln!("{message}");
+    } else {
+        println!("{message}");
+    }
+
+    let state = STATE.lock().unwrap();
+    let mut all = vec![
+        ("MESSAGE", message.to_string()),
+        ("MESSAGE_ID", message_id.to_string()),
+        ("PRIORITY", priority.to_string()),
+        ("SYSLOG_IDENTIFIER", "btrfs-migrator".to_string()),
+    ];
+    all.extend(state.context.iter().cloned());
+    if let Some(phase) = &state.phase {
+        all.push(("PHASE", phase.clone()));
+    }
+    all.extend(fields.iter().map(|(key, value)| (*key, value.to_string())));
+    // Silently: journald is not running when the migrator is used by hand and the console has the message already.
+    let _ = send(&all);
+}
+
+// Set a field for all following events, replacing an earlier value.
+pub fn context(key: &'static str, value: impl Display) {
+    let mut state = STATE.lock().unwrap();
+    state.context.retain(|(k, _)| *k != key);
+    state.context.push((key, value.to_string()));
+}
+
+pub fn started(from: u8, to: u8) {
+    context("FROM_LAYOUT", from);
+    context("TO_LAYOUT", to);
+    report::started(from, to);
+    events::started(from, to);
+    log(
+        STARTED,
+        INFO,
+        &format!("Migrating rootfs layout v{from} to v{to}"),
+        &[],
+    );
+}
+
+pub fn finished(message: &str) {
+    report::result("finished", message);
+    events::result("finished", message);
+    log(FINISHED, INFO, message, &[]);
+}
+
+pub fn skipped(message: &str) {
+    report::result("skipped", message);
+    events::result("skipped", message);
+    log(SKIPPED, INFO, message, &[]);
+}
+
+pub fn warning(message: &str, fields: &[(&str, &dyn Display)]) {
+    report::warning(message, field(fields, "PATH"));
+    events::warning(message, field(fields, "PATH"), current_phase());
+    log(WARNING, WARN, message, fields);
+}
+
+pub fn failed(error: &(dyn Error + 'static)) {
+    let errno = help::errno(error);
+    let message = format!("Migration failed: {error}");
+    let class = help::classify(error);
+    let code = class.code();
+    report::result("failed", &message);
+    report::diagnostic(code);
+    events::failed(&message, code, &class.url(), current_phase());
+    match errno {
+        Some(errno) => log(
+            FAILED,
+            ERR,
+            &message,
+            &[("DIAGNOSTIC", &code), ("ERRNO", &errno)],
+        ),
+        None => log(FAILED, ERR, &message, &[("DIAGNOSTIC", &code)]),
+    }
+}
+
+// The phase a failure happened in, if any.
+pub fn current_phase() -> Option<String> {
+    STATE.lock().unwrap().phase.clone()
+}
+
+// A step of the migration. Failures while it is running are attributed to it, finish() records how long it took.
+pub struct Phase {
+    name: String,
+    started: Instant,
+}
+
+pub fn phase(name: &str) -> Phase {
+    STATE.lock().unwrap().phase = Some(name.to_string());
+    events::phase_started(name);
+    Phase {
+        name: name.to_string(),
+        started: Instant::now(),
+    }
+}
+
+impl Phase {
+    pub fn finish(self, fields: &[(&str, &dyn Display)]) {
+        let duration = self.started.elapsed();
+        let usec = duration.as_micros();
+        let path = field(fields, "PATH");
+        let bytes = field(fields, "BYTES").and_then(|bytes| bytes.parse().ok());
+        report::phase(&self.name, usec, path.clone(), bytes);
+        events::phase_finished(&self.name, usec, path, bytes);
+        let mut all: Vec<(&str, &dyn Display)> = vec![("DURATION_USEC", &usec)];
+        all.extend_from_slice(fields);
+        log(
+            PHASE_FINISHED,
+            INFO,
+            &format!("Finished {} in {:.1}s", self.name, duration.as_secs_f64()),
+            &all,
+        );
+        STATE.lock().unwrap().phase = None;
+    }
+}
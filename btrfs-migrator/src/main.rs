@@ -5,18 +5,51 @@ use std::{
     env,
     error::Error,
     fs::{self},
-    io::{self, Write},
-    os::unix::fs::MetadataExt,
+    os::unix::{fs::MetadataExt, process::CommandExt},
     path::{Path, PathBuf},
     process::Command,
 };
 #[macro_use(defer)]
 extern crate scopeguard;
-use dialoguer::{self, Confirm};
-use fstab::FsTab;
+mod attributes;
+mod bundle;
+mod cancel;
+mod convert;
+mod durability;
+mod events;
+mod fault;
+mod fstab;
+mod help;
+mod i18n;
+mod journal;
+mod mountinfo;
+mod nested;
+mod notify;
+mod overlay;
+mod plymouth;
+mod policy;
+mod prompt;
+mod report;
+mod swap;
+use cancel::checkpoint;
+use durability::{fsync_dir, rename_exchange, sync_fs};
+use help::{Class, Context};
+use i18n::{tr, trn};
 use libbtrfsutil::{
     CreateSnapshotOptions, CreateSubvolumeOptions, DeleteSubvolumeOptions, is_subvolume,
+    set_subvolume_read_only, subvolume_id,
 };
+use mountinfo::ensure_unmounted;
+use policy::{Mode, Policy};
+use prompt::Question;
+
+// The v1 data subvolumes and where run() puts their snapshots in @system.
+const SUBVOL_TARGETS: [(&str, &str); 4] = [
+    ("@home", "home"),
+    ("@root", "root"),
+    ("@containers", "var/lib/containers"),
+    ("@docker", "var/lib/docker"),
+];
 
 fn find_rootfs_v1(root: &Path) -> Option<PathBuf> {
     let subvols = fs::read_dir(root).ok()?;
@@ -40,9 +73,7 @@ fn find_rootfs_v1(root: &Path) -> Option<PathBuf> {
             // This is not a useful/valid rootfs v1 subvolume.
             continue;
         }
-        let mut parts = name.splitn(2, '_');
-        let _prefix = parts.next().unwrap();
-        let version = parts.next().unwrap();
+        let (_prefix, version) = name.split_once('_').unwrap();
         match version.parse::<u64>() {
             Ok(version) => {
                 if candidate.is_none() || candidate.as_ref().unwrap().version < version {
@@ -70,7 +101,36 @@ fn find_rootfs_v1(root: &Path) -> Option<PathBuf> {
     }
 }
 
-fn run(root: &Path) -> Result<(), Box<dyn Error>> {
+// Apparent size of the regular files below `path`, for the logs. Unreadable bits count as empty.
+fn tree_size(path: &Path) -> u64 {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return 0;
+    };
+    if metadata.is_dir() {
+        fs::read_dir(path)
+            .map(|entries| {
+                entries
+                    .flatten()
+                    .map(|entry| tree_size(&entry.path()))
+                    .sum()
+            })
+            .unwrap_or(0)
+    } else if metadata.is_file() {
+        metadata.len()
+    } else {
+        0
+    }
+}
+
+// cp in its own process group, so a Ctrl-C on the tty reaches only us and we get to stop at the next checkpoint instead
+// of the copy failing underneath us.
+fn cp() -> Command {
+    let mut command = Command::new("cp");
+    command.process_group(0);
+    command
+}
+
+fn run(root: &Path, policy: &Policy) -> Result<(), Box<dyn Error>> {
     env::set_current_dir(root)?;
 
     let system_path = root.join("@system");
@@ -78,6 +138,12 @@ fn run(root: &Path) -> Result<(), Box<dyn Error>> {
         println!("@system exists already. Skipping migration.");
         return Ok(());
     }
+    if policy.mode == Mode::Defer {
+        return Err(Class::Policy.error(
+            "kde-linux.migrate=defer, but this image cannot boot without @system. \
+             Boot an image from before May 2025 to put off the migration.",
+        ));
+    }
 
     // Wait for devices to settle down a bit, otherwise we risk breaking plymouth and printing into the void, leaving
     // the user without any indication what is going on.
@@ -87,12 +153,15 @@ fn run(root: &Path) -> Result<(), Box<dyn Error>> {
         .arg("--timeout=8")
         .status();
 
-    let _ = Command::new("plymouth")
-        .arg("display-message")
-        .arg("--text=Migrating to v2 rootfs. Can take a while.")
-        .status();
+    let headline = tr("Migrating to v2 rootfs. Can take a while.", &[]);
+    plymouth::begin_progress();
+    plymouth::status(&headline);
+    plymouth::progress(0);
+    journal::started(1, 2);
 
+    let phase = journal::phase("create-import");
     let import_path = root.join("@system.import");
+    ensure_unmounted(&import_path)?;
     if import_path.exists() {
         println!("@system.import exists. Deleting it.");
         match DeleteSubvolumeOptions::new()
@@ -106,70 +175,143 @@ fn run(root: &Path) -> Result<(), Box<dyn Error>> {
     CreateSubvolumeOptions::new()
         .create(&import_path)
         .map_err(|error| format!("Problem creating subvolume {import_path:?}: {error:?}"))?;
+    fault::inject("v2-after-create-import")?;
+    phase.finish(&[("PATH", &import_path.display())]);
 
     env::set_current_dir(&import_path)?;
     println!("Current directory: {:?}", env::current_dir()?);
 
-    // May or may not exist. Don't trip over it!
-    let fstab = FsTab::new(&root.join("@etc-overlay/upper/fstab"));
-    let mut concerning_fstab_entries = 0;
-    for entry in fstab.get_entries().unwrap_or_default() {
-        if entry.vfs_type != "swap" {
-            concerning_fstab_entries += 1;
-        }
+    let phase = journal::phase("fstab-check");
+    // For subvolid= references to the data subvolumes.
+    let subvol_ids: Vec<(u64, &str)> = SUBVOL_TARGETS
+        .iter()
+        .filter_map(|(subvol, _)| Some((subvolume_id(root.join(subvol)).ok()?, *subvol)))
+        .collect();
+    // Either may or may not exist. Don't trip over it!
+    let upper_etc = root.join("@etc-overlay/upper");
+    let fstab = fstab::plan(&upper_etc, &subvol_ids).map_err(|e| {
+        Class::Fstab.caused_by(
+            format!("Failed to read fstab and mount units in {upper_etc:?}: {e}"),
+            e,
+        )
+    })?;
+    let actions = fstab.actions();
+    for action in &actions {
+        println!("{}: {} → {}", action.file, action.line, action.description);
     }
-    if concerning_fstab_entries > 0 {
-        let _ = Command::new("plymouth").arg("hide-splash").status();
-
-        let _ = qr2term::print_qr("https://community.kde.org/KDE_Linux/RootFSv2");
-
-        println!(
-            "Found {concerning_fstab_entries} concerning fstab entries. This suggests you have a more complicated fstab setup that we cannot auto-migrate. \
-If nothing critically important is managed by fstab you can let the auto-migration run. If you have entries that are required for the system to boot you should manually migrate to @system."
+    let unmigratable: Vec<String> = actions
+        .iter()
+        .filter(|action| !action.migratable)
+        .map(|action| {
+            format!(
+                "{}: {}\n  → {}",
+                action.file, action.line, action.description
+            )
+        })
+        .collect();
+    if !unmigratable.is_empty() {
+        // Translatable strings have to stay on one line, see i18n.rs.
+        let count = unmigratable.len() as u64;
+        let details = format!(
+            "{}\n\n{}",
+            trn(
+                "Found {count} fstab, crypttab or mount unit entry that cannot be migrated automatically. It is copied to @system unchanged, but may no longer work. If it is not required for the system to boot you can let the migration run. If it is, you should manually migrate to @system.",
+                "Found {count} fstab, crypttab or mount unit entries that cannot be migrated automatically. They are copied to @system unchanged, but may no longer work. If none are required for the system to boot you can let the migration run. If they are, you should manually migrate to @system.",
+                count,
+                &[("count", &count)],
+            ),
+            unmigratable.join("\n")
         );
-        io::stdout().flush().unwrap();
-
-        let migrate = Confirm::new()
-            .with_prompt("Do you want to continue with auto-migration?")
-            .interact()
-            .unwrap();
+        let summary = trn(
+            "Found {count} fstab, crypttab or mount unit entry that cannot be auto-migrated.\nIf it is not required for booting, the migration can continue.\nSee community.kde.org/KDE_Linux/RootFSv2",
+            "Found {count} fstab, crypttab or mount unit entries that cannot be auto-migrated.\nIf none are required for booting, the migration can continue.\nSee community.kde.org/KDE_Linux/RootFSv2",
+            count,
+            &[("count", &count)],
+        );
+        let migrate = match prompt::confirm(
+            policy,
+            &Question {
+                summary: &summary,
+                details: &details,
+                help_url: "https://community.kde.org/KDE_Linux/RootFSv2",
+                prompt: &tr("Do you want to continue with auto-migration?", &[]),
+            },
+        ) {
+            Ok(migrate) => migrate,
+            Err(e) => {
+                eprintln!("{e}");
+                eprintln!(
+                    "{}",
+                    tr("Nothing was migrated. The next boot tries again.", &[])
+                );
+                return Err(Class::Fstab.error("Migration cancelled at the fstab prompt"));
+            }
+        };
 
         if !migrate {
             Command::new("systemctl")
                 .arg("reboot")
                 .status()
                 .expect("failed to execute systemctl reboot");
-            return Err("Concerning fstab entries found".into());
+            return Err(Class::Fstab
+                .error("Declined to migrate fstab entries that cannot be migrated automatically"));
         }
     }
+    phase.finish(&[
+        (
+            "FSTAB_ENTRIES",
+            &actions
+                .iter()
+                .filter(|action| action.file == "fstab")
+                .count(),
+        ),
+        (
+            "CRYPTTAB_ENTRIES",
+            &actions
+                .iter()
+                .filter(|action| action.file == "crypttab")
+                .count(),
+        ),
+        (
+            "MOUNT_UNITS",
+            &actions
+                .iter()
+                .filter(|action| !["fstab", "crypttab"].contains(&action.file))
+                .count(),
+        ),
+        ("FSTAB_CONCERNING_ENTRIES", &unmigratable.len()),
+    ]);
 
     let rootfs_v1 = match find_rootfs_v1(root) {
         Some(path) => path,
-        None => return Err("No legacy rootfs v1 found. Migration impossible.".into()),
+        None => {
+            return Err(
+                Class::NoLegacyRoot.error("No legacy rootfs v1 found. Migration impossible.")
+            );
+        }
     };
 
-    for dir in ["etc", "var"] {
+    let next_boot =
+        "@system.import is incomplete. The next boot discards it and restarts the migration.";
+
+    // Copying var is what takes time, the snapshots are instant.
+    for (dir, percent) in [("etc", 10), ("var", 80)] {
+        checkpoint(next_boot)?;
+        plymouth::status(&format!(
+            "{headline}\n{}",
+            tr("Copying /{dir}", &[("dir", &dir)])
+        ));
+        let phase = journal::phase(&format!("copy-{dir}"));
         let compose_dir = rootfs_v1.join(dir);
+        // A leftover overlay from an earlier attempt would end up as our lowerdir.
+        ensure_unmounted(&compose_dir)?;
 
-        let mount_result = Command::new("mount")
-            .arg("--verbose")
-            .arg("--types")
-            .arg("overlay")
-            .arg("--options")
-            .arg(format!(
-                "ro,lowerdir={},upperdir={},workdir={},index=off,metacopy=off",
-                compose_dir.to_string_lossy(),
-                root.join(format!("@{dir}-overlay/upper")).to_string_lossy(),
-                root.join(format!("@{dir}-overlay/work")).to_string_lossy()
-            ))
-            .arg("overlay")
-            .arg(&compose_dir)
-            .status()
-            .expect("Failed to mount overlay for etc/var");
-        if !mount_result.success() {
-            println!("Failed to mount {}", compose_dir.display());
-            return Err("Failed to mount compose dir".into());
-        }
+        overlay::mount(
+            &compose_dir,
+            &root.join(format!("@{dir}-overlay/upper")),
+            &root.join(format!("@{dir}-overlay/work")),
+            &compose_dir,
+        )?;
         defer! {
             println!("Unmounting overlay for {}", dir);
             Command::new("umount")
@@ -178,12 +320,18 @@ If nothing critically important is managed by fstab you can let the auto-migrati
             .expect("Failed to unmount overlay for etc/var");
         }
 
+        // Like on fresh installs, see also SPLIT_LAYOUTS. cp copies into it rather than creating a directory.
+        if dir == "etc" {
+            CreateSubvolumeOptions::new()
+                .create(dir)
+                .context(|| format!("Problem creating subvolume {dir:?}"))?;
+        }
         println!(
             "Copying {} to {}",
             compose_dir.display(),
             import_path.join(dir).display()
         );
-        let cp_result = Command::new("cp")
+        let cp_result = cp()
             .arg("--recursive")
             .arg("--archive")
             .arg("--reflink=auto")
@@ -194,23 +342,28 @@ If nothing critically important is managed by fstab you can let the auto-migrati
             .expect("Failed to copy upper dir");
         if !cp_result.success() {
             println!("Failed to copy upper dir {compose_dir:?} to {dir:?}");
-            return Err("Failed to copy upper dir".into());
+            return Err(Class::Copy.error("Failed to copy upper dir"));
         }
+        plymouth::progress(percent);
+        fault::inject(&format!("v2-after-copy-{dir}"))?;
+        let target = import_path.join(dir);
+        phase.finish(&[("PATH", &target.display()), ("BYTES", &tree_size(&target))]);
     }
 
-    let subvol_targets = [
-        ("@home", "home"),
-        ("@root", "root"),
-        ("@containers", "var/lib/containers"),
-        ("@docker", "var/lib/docker"),
-    ];
-
-    for (subvol, target) in subvol_targets {
+    plymouth::status(&format!(
+        "{headline}\n{}",
+        tr("Moving data subvolumes", &[])
+    ));
+    for (subvol, target) in SUBVOL_TARGETS {
+        checkpoint(next_boot)?;
+        let phase = journal::phase(&format!("snapshot-{subvol}"));
         println!("Snapshotting {} to {}", root.join(subvol).display(), target);
         let target_path = Path::new(target);
 
-        // Inside var the target_path may already exist if they predate the subvolumes. Originally contianers and docker were not subvolumes.
-        // Make sure to throw the data away before trying to snapshot, otherwise the snapshot will fail.
+        // Inside var the target_path may already exist if they predate the subvolumes. Originally
+        // containers and docker were not subvolumes. Make sure to throw the data away before trying
+        // to snapshot, otherwise the snapshot will fail.
+        ensure_unmounted(target_path)?;
         if target_path.exists() {
             println!("Removing pre-existing directory {target_path:?}");
             fs::remove_dir_all(target_path)?;
@@ -232,12 +385,57 @@ If nothing critically important is managed by fstab you can let the auto-migrati
         CreateSnapshotOptions::new()
             .recursive(true)
             .create(root.join(subvol), Path::new(target))?;
+        fault::inject(&format!("v2-after-snapshot-{subvol}"))?;
+        phase.finish(&[("PATH", &import_path.join(target).display())]);
     }
 
+    // Now that all of them have been copied or snapshotted.
+    let swapfiles = fstab.swapfiles();
+    let mut recreated = Vec::new();
+    if !swapfiles.is_empty() {
+        checkpoint(next_boot)?;
+        let phase = journal::phase("swapfiles");
+        recreated = swap::migrate(&import_path, &swapfiles)?;
+        fault::inject("v2-after-swapfiles")?;
+        phase.finish(&[
+            ("PATH", &import_path.join(swap::SUBVOLUME).display()),
+            ("SWAPFILES", &recreated.len()),
+        ]);
+    }
+
+    // Only now that it is known which swapfiles made it.
+    let etc = import_path.join("etc");
+    fstab
+        .write(&etc, &recreated)
+        .context(|| format!("Failed to migrate fstab and mount units in {etc:?}"))?;
+
+    checkpoint(next_boot)?;
+    let phase = journal::phase("publish");
+    // The rename publishes @system, so all of it needs to be on disk first.
+    sync_fs(&import_path).context(|| format!("Failed to sync {import_path:?}"))?;
+    fault::inject("v2-before-rename-import")?;
+    ensure_unmounted(&import_path)?;
     println!("Renaming {import_path:?} to {system_path:?}");
-    fs::rename(import_path, system_path)?; // fatal problem
+    fs::rename(&import_path, &system_path)?; // fatal problem
+    plymouth::progress(100);
+    fsync_dir(root).context(|| format!("Failed to sync {root:?}"))?;
+    fault::inject("v2-after-rename-import")?;
+    phase.finish(&[("PATH", &system_path.display())]);
+
+    // Nothing of v1 gets deleted, it all stays around for the user to clean up once happy with v2.
+    for (subvol, _) in SUBVOL_TARGETS {
+        report::leftover(root, &root.join(subvol));
+    }
+    for leftover in [
+        rootfs_v1,
+        root.join("@etc-overlay"),
+        root.join("@var-overlay"),
+    ] {
+        report::leftover(root, &leftover);
+    }
 
-    return Ok(());
+    journal::finished("Migrated rootfs layout v1 to v2");
+    Ok(())
 }
 
 // Recursively walk `src`, find any btrfs subvolumes, and replace the corresponding
@@ -254,20 +452,13 @@ fn snapshot_nested_subvolumes(src: &Path, dst: &Path) -> Result<(), Box<dyn Erro
         }
 
         let nested_dst = dst.join(nested.file_name());
-        // Subvolume roots are always inode 256.
-        let subvolume = nested.metadata()?.ino() == 256
-            && is_subvolume(&nested_src)
-                .map_err(|e| format!("Failed to stat {nested_src:?}: {e:?}"))?;
-
-        if subvolume {
+        if subvolume(&nested_src)? {
             println!("Snapshotting nested subvolume {nested_src:?} -> {nested_dst:?}");
             fs::remove_dir_all(&nested_dst)?;
             CreateSnapshotOptions::new()
                 .recursive(true)
                 .create(&nested_src, &nested_dst)
-                .map_err(|e| {
-                    format!("Failed to snapshot {nested_src:?} to {nested_dst:?}: {e:?}")
-                })?;
+                .context(|| format!("Failed to snapshot {nested_src:?} to {nested_dst:?}"))?;
             // Don't descend further as recursive(true) already handled this subvolume's children.
         } else {
             snapshot_nested_subvolumes(&nested_src, &nested_dst)?;
@@ -276,40 +467,111 @@ fn snapshot_nested_subvolumes(src: &Path, dst: &Path) -> Result<(), Box<dyn Erro
     Ok(())
 }
 
-// remove_dir_all() cannot rmdir the per-user subvolumes inside a staging dir.
-fn remove_staging_dir(dir: &Path) -> Result<(), Box<dyn Error>> {
+// Subvolume roots are always inode 256. Checked with lstat() first, so a symlink is never mistaken for what it points
+// to and btrfs only gets asked about the rare directory that passes.
+fn subvolume(path: &Path) -> Result<bool, Box<dyn Error>> {
+    Ok(fs::symlink_metadata(path)?.ino() == 256
+        && is_subvolume(path).context(|| format!("Failed to stat {path:?}"))?)
+}
+
+// remove_dir_all() cannot rmdir subvolumes, wherever they are nested.
+fn remove_tree(dir: &Path) -> Result<(), Box<dyn Error>> {
     for entry in fs::read_dir(dir)? {
         let entry = entry?;
         let path = entry.path();
         if !entry.file_type()?.is_dir() {
             continue;
         }
-        if is_subvolume(&path).unwrap_or(false) {
-            println!("Deleting leftover subvolume {path:?}");
+        if subvolume(&path)? {
+            println!("Deleting nested subvolume {path:?}");
             DeleteSubvolumeOptions::new()
                 .recursive(true)
                 .delete(&path)
-                .map_err(|e| format!("Failed to delete leftover subvolume {path:?}: {e:?}"))?;
+                .context(|| format!("Failed to delete subvolume {path:?}"))?;
+        } else {
+            remove_tree(&path)?;
         }
     }
     fs::remove_dir_all(dir)?;
     Ok(())
 }
 
-fn run_v3(root: &Path) -> Result<(), Box<dyn Error>> {
+// Delete the pre-migration home, or with kde-linux.migrate.keep-snapshots park it read-only at the top level, where
+// snapshots of @system don't drag it along.
+fn retire_old_home(root: &Path, old_home: &Path, policy: &Policy) -> Result<(), Box<dyn Error>> {
+    let kept = root.join("@home.pre-v3");
+    if policy.keep_snapshots {
+        if kept.exists() {
+            journal::warning(
+                &format!(
+                    "{kept:?} is left from an earlier migration. Not keeping {old_home:?} as well."
+                ),
+                &[("PATH", &old_home.display())],
+            );
+        } else {
+            println!("Keeping old home subvolume {old_home:?} as read-only {kept:?}");
+            fs::rename(old_home, &kept)
+                .context(|| format!("Failed to move {old_home:?} to {kept:?}"))?;
+            fsync_dir(root).context(|| format!("Failed to sync {root:?}"))?;
+            report::leftover(root, &kept);
+            set_subvolume_read_only(&kept, true)
+                .context(|| format!("Failed to make {kept:?} read-only"))?;
+            return Ok(());
+        }
+    }
+
+    println!("Deleting old home subvolume {old_home:?}");
+    DeleteSubvolumeOptions::new()
+        .recursive(true)
+        .delete(old_home)
+        .context(|| format!("Failed to delete {old_home:?}"))?;
+    Ok(())
+}
+
+fn run_v3(root: &Path, policy: &Policy) -> Result<(), Box<dyn Error>> {
+    let system = root.join("@system");
     let system_home = root.join("@system/home");
     let system_home_tmp = root.join("@system/home.v3tmp");
     let system_home_old = root.join("@system/home.v3old");
-    let _ = Command::new("plymouth")
-        .arg("display-message")
-        .arg("--text=Migrating to v3 rootfs. This will take a while.")
-        .status();
+    let headline = tr("Migrating to v3 rootfs. This will take a while.", &[]);
+    plymouth::begin_progress();
+    plymouth::status(&headline);
+    journal::started(2, 3);
     println!("Migrating @system/home from subvolume to regular directory with per-user subvolumes");
 
+    if root.join("@system/home.v2old").exists() || root.join("@system/home.v2tmp").exists() {
+        return Err(Class::Inconsistent.error(
+            "A rollback was interrupted. Run `btrfs-migrator rollback` again to let it finish \
+             before migrating.",
+        ));
+    }
+
+    let phase = journal::phase("recover");
+    for path in [&system_home, &system_home_tmp, &system_home_old] {
+        ensure_unmounted(path)?;
+    }
+
+    // Crashed after exchange(tmp↔home) but before rename(tmp→old). tmp is only ever created as a regular directory,
+    // so a subvolume there is the original home and home already is the new layout.
+    if system_home_tmp.exists() && is_subvolume(&system_home_tmp).unwrap_or(false) {
+        if system_home_old.exists() {
+            return Err(Class::Inconsistent.error(format!(
+                "Both {system_home_tmp:?} and {system_home_old:?} exist and {system_home_tmp:?} is \
+                 a subvolume. Refusing to touch either."
+            )));
+        }
+        println!(
+            "Detected swapped v3 migration: moving {system_home_tmp:?} to {system_home_old:?}"
+        );
+        fs::rename(&system_home_tmp, &system_home_old)?;
+        fsync_dir(&system).context(|| format!("Failed to sync {system:?}"))?;
+    }
+
     // Clean up any leftover staging dirs from a previous failed run.
     if system_home_old.exists() {
         if !system_home.exists() {
-            // Crashed after rename(home→old) but before rename(tmp→home).
+            // Crashed after rename(home→old) but before rename(tmp→home). Only older versions of the migrator
+            // renamed in two steps, but their leftovers may still be around.
             // home.v3old is the only surviving copy of user data so restore it.
             eprintln!(
                 "Detected partial v3 migration: {system_home:?} is missing but \
@@ -317,55 +579,84 @@ fn run_v3(root: &Path) -> Result<(), Box<dyn Error>> {
             );
             if system_home_tmp.exists() {
                 println!("Removing incomplete staging dir {system_home_tmp:?}");
-                remove_staging_dir(&system_home_tmp)?;
+                remove_tree(&system_home_tmp)?;
             }
             fs::rename(&system_home_old, &system_home).map_err(|e| {
-                format!(
-                    "CRITICAL: failed to restore home from {system_home_old:?}: {e}. \
-                     User data may still be in {system_home_old:?}."
+                Class::Inconsistent.caused_by(
+                    format!(
+                        "CRITICAL: failed to restore home from {system_home_old:?}: {e}. \
+                         User data may still be in {system_home_old:?}."
+                    ),
+                    e,
                 )
             })?;
+            fsync_dir(&system).context(|| format!("Failed to sync {system:?}"))?;
             println!("Restored {system_home_old:?} -> {system_home:?}. Retrying migration.");
         } else if is_subvolume(&system_home).unwrap_or(false) {
-            // rename(home -> old) is what creates home.v3old, so the two only coexist once
+            // exchange(tmp <-> home) + rename(tmp -> old) is what creates home.v3old, so the two only coexist once
             // home has been replaced by the new regular directory. Deleting either of them
             // here could throw away the only copy of the user data.
-            return Err(format!(
+            return Err(Class::Inconsistent.error(format!(
                 "Both {system_home:?} and {system_home_old:?} exist and {system_home:?} is \
                  still a subvolume. Refusing to touch either."
-            )
-            .into());
+            )));
         } else {
             // home is a regular dir, so a previous run completed and only failed to clean up.
             println!("Cleaning up leftover {system_home_old:?} from previous run");
-            DeleteSubvolumeOptions::new()
-                .recursive(true)
-                .delete(&system_home_old)
-                .map_err(|e| format!("Failed to delete leftover {system_home_old:?}: {e:?}"))?;
+            retire_old_home(root, &system_home_old, policy)?;
         }
     }
 
     if system_home_tmp.exists() {
         println!("Cleaning up leftover {system_home_tmp:?} from previous run");
-        remove_staging_dir(&system_home_tmp)?;
+        remove_tree(&system_home_tmp)?;
     }
+    phase.finish(&[]);
 
     if !system_home.exists() {
-        println!("{system_home:?} does not exist. Nothing to migrate.");
+        journal::skipped(&format!(
+            "{system_home:?} does not exist. Nothing to migrate."
+        ));
         return Ok(());
     }
-    if !is_subvolume(&system_home)
-        .map_err(|e| format!("Failed to stat {system_home:?}: {e:?}"))?
-    {
-        println!("{system_home:?} is already a regular directory. Nothing to do.");
+    if !is_subvolume(&system_home).context(|| format!("Failed to stat {system_home:?}"))? {
+        journal::skipped(&format!(
+            "{system_home:?} is already a regular directory. Nothing to do."
+        ));
         return Ok(());
     }
+    // Only now that no interrupted attempt is left behind, the v2 layout boots fine as long as it is intact.
+    if policy.mode == Mode::Defer {
+        journal::skipped(&format!(
+            "Not migrating {system_home:?} this boot (kde-linux.migrate=defer)."
+        ));
+        return Ok(());
+    }
+
+    let next_boot =
+        "@system/home is untouched. The next boot discards home.v3tmp and retries the migration.";
 
     // Stage into a sibling directory so that if we crash mid-way, @system/home is still a subvolume
     // and the next boot will retry the migration cleanly.
     fs::create_dir(&system_home_tmp)?;
-    for entry in fs::read_dir(&system_home)? {
-        let entry = entry?;
+    fault::inject("v3-after-create-tmp")?;
+    // Collected upfront to know how far along we are.
+    let entries = fs::read_dir(&system_home)?.collect::<Result<Vec<_>, _>>()?;
+    for (index, entry) in entries.iter().enumerate() {
+        checkpoint(next_boot)?;
+        plymouth::progress((index * 100 / entries.len()) as u8);
+        plymouth::status(&format!(
+            "{headline}\n{}",
+            tr(
+                "Moving {name}",
+                &[("name", &entry.file_name().to_string_lossy())]
+            )
+        ));
+        fault::inject(&format!(
+            "v3-before-copy-{}",
+            entry.file_name().to_string_lossy()
+        ))?;
+        let phase = journal::phase(&format!("stage-{}", entry.file_name().to_string_lossy()));
         let src = entry.path();
         let file_type = entry.file_type()?;
         let dst = system_home_tmp.join(entry.file_name());
@@ -375,11 +666,14 @@ fn run_v3(root: &Path) -> Result<(), Box<dyn Error>> {
         // is copied as a symlink rather than dereferenced into a subvolume.
         if !file_type.is_dir() {
             if !file_type.is_file() && !file_type.is_symlink() {
-                eprintln!("Warning: not copying {src:?}, unsupported file type {file_type:?}");
+                journal::warning(
+                    &format!("Not copying {src:?}, unsupported file type {file_type:?}"),
+                    &[("PATH", &src.display())],
+                );
                 continue;
             }
             println!("Copying {src:?} to {dst:?}");
-            let cp_result = Command::new("cp")
+            let cp_result = cp()
                 .arg("--archive")
                 .arg("--reflink=auto")
                 .arg("--no-target-directory")
@@ -388,18 +682,20 @@ fn run_v3(root: &Path) -> Result<(), Box<dyn Error>> {
                 .status()
                 .expect("Failed to copy home entry");
             if !cp_result.success() {
-                return Err(format!("Failed to copy {src:?} to {dst:?}").into());
+                return Err(Class::Copy.error(format!("Failed to copy {src:?} to {dst:?}")));
             }
+            phase.finish(&[("PATH", &dst.display()), ("BYTES", &tree_size(&dst))]);
             continue;
         }
 
         println!("Creating user subvolume at {dst:?}");
+        report::user(&entry.file_name().to_string_lossy());
         CreateSubvolumeOptions::new()
             .create(&dst)
-            .map_err(|e| format!("Failed to create subvolume {dst:?}: {e:?}"))?;
+            .context(|| format!("Failed to create subvolume {dst:?}"))?;
 
         // Copy everything including nested subvolume dirs (we'll replace those with snapshots after)
-        let cp_result = Command::new("cp")
+        let cp_result = cp()
             .arg("--recursive")
             .arg("--archive")
             .arg("--reflink=auto")
@@ -408,70 +704,488 @@ fn run_v3(root: &Path) -> Result<(), Box<dyn Error>> {
             .status()
             .expect("Failed to copy user home");
         if !cp_result.success() {
-            return Err(format!("Failed to copy {src:?} to {dst:?}").into());
+            return Err(Class::Copy.error(format!("Failed to copy {src:?} to {dst:?}")));
         }
 
         // Recursively replace any nested subvolume dirs (which cp copied as plain dirs) with proper
         // snapshots. This handles deeply nested cases.
         snapshot_nested_subvolumes(&src, &dst)?;
+
+        // Staged, so nothing is in use yet and an interrupted attempt is thrown away along with home.v3tmp.
+        nested::apply(
+            &dst,
+            &policy.user_subvolumes,
+            &format!("v3-{}", entry.file_name().to_string_lossy()),
+            next_boot,
+        )?;
+        phase.finish(&[("PATH", &dst.display()), ("BYTES", &tree_size(&dst))]);
     }
 
-    println!("Renaming {system_home:?} to {system_home_old:?}");
-    fs::rename(&system_home, &system_home_old)?;
+    // Last chance to stop. Past the exchange we finish, cleaning up is quicker than explaining a half done state.
+    checkpoint(next_boot)?;
+    let phase = journal::phase("exchange");
 
-    println!("Renaming {system_home_tmp:?} to {system_home:?}");
-    if let Err(e) = fs::rename(&system_home_tmp, &system_home) {
-        eprintln!("Fatal: failed to rename {system_home_tmp:?} to {system_home:?}: {e}");
-        eprintln!("Restoring original home subvolume from {system_home_old:?}");
-        fs::rename(&system_home_old, &system_home)
-            .map_err(|re| format!("CRITICAL: failed to restore original home: {re}. System may be unbootable. Original home is at {system_home_old:?}"))?;
-        return Err(format!("Migration failed, original home restored: {e}").into());
-    }
+    // The staged copy must be fully on disk before the exchange makes it the live home.
+    sync_fs(&system).context(|| format!("Failed to sync {system:?}"))?;
+    fault::inject("v3-before-exchange")?;
+    ensure_unmounted(&system_home)?;
+    ensure_unmounted(&system_home_tmp)?;
+
+    // Atomic, so at any point in time home is either the complete old or the complete new layout. If this fails
+    // nothing changed and the next boot retries.
+    println!("Exchanging {system_home_tmp:?} and {system_home:?}");
+    rename_exchange(&system_home_tmp, &system_home)
+        .context(|| format!("Failed to exchange {system_home_tmp:?} and {system_home:?}"))?;
+    fsync_dir(&system).context(|| format!("Failed to sync {system:?}"))?;
+    fault::inject("v3-after-exchange")?;
+    plymouth::progress(100);
+    phase.finish(&[("PATH", &system_home.display())]);
+
+    println!("Renaming {system_home_tmp:?} to {system_home_old:?}");
+    fs::rename(&system_home_tmp, &system_home_old)?;
+    fsync_dir(&system).context(|| format!("Failed to sync {system:?}"))?;
+    fault::inject("v3-after-rename-tmp-to-old")?;
 
     // Only delete the old subvolume once we know the new layout is in place
-    println!("Deleting old home subvolume {system_home_old:?}");
-    let _ = DeleteSubvolumeOptions::new()
-        .recursive(true)
-        .delete(&system_home_old)
-        .inspect_err(|e| {
-            eprintln!("Warning: failed to delete old home subvolume {system_home_old:?}: {e}");
-            eprintln!("The migration succeeded but deleting the old subvolume failed. {system_home_old:?} can be manually deleted.");
-        });
+    let _ = retire_old_home(root, &system_home_old, policy).inspect_err(|e| {
+        report::leftover(root, &system_home_old);
+        journal::warning(
+            &format!(
+                "The migration succeeded but cleaning up the old subvolume failed: {e}. \
+                 {system_home_old:?} can be manually deleted."
+            ),
+            &[("PATH", &system_home_old.display())],
+        );
+    });
+    let _ = sync_fs(&system);
 
+    journal::finished("Migrated rootfs layout v2 to v3");
     Ok(())
 }
 
+// Whether run_v3 has work to do, see also rootfs-transition.
+fn v3_pending(root: &Path) -> bool {
+    is_subvolume(root.join("@system/home")).unwrap_or(false)
+        || ["home.v3old", "home.v3tmp", "home.v2old", "home.v2tmp"]
+            .iter()
+            .any(|name| root.join("@system").join(name).exists())
+}
+
+// A directory in @system that a layout makes a subvolume of its own, see convert.rs.
+struct Split {
+    // Below @system.
+    path: &'static str,
+    // Below `path`, they and the directories directly in them get copy-on-write disabled, for the files in there
+    // already as well. Only where they exist.
+    nocow: &'static [&'static str],
+}
+
+// Layouts after v3, each moving directories out of the way of @system snapshots. Older images see no difference, so
+// there is nothing to roll back.
+const SPLIT_LAYOUTS: [(u8, &[Split]); 3] = [
+    // Flatpak runtimes and apps are large and can be downloaded again.
+    (
+        4,
+        &[Split {
+            path: "var/lib/flatpak",
+            nocow: &[],
+        }],
+    ),
+    // Rolling back the configuration should not roll back the logs of what happened, nor caches. The journal does
+    // its own writes in place, copy-on-write only fragments it. It writes into a directory per machine ID.
+    (
+        5,
+        &[
+            Split {
+                path: "var/log",
+                nocow: &["journal"],
+            },
+            Split {
+                path: "var/cache",
+                nocow: &[],
+            },
+            Split {
+                path: "var/tmp",
+                nocow: &[],
+            },
+        ],
+    ),
+    // Fresh installs have etc as a subvolume, and so does run() nowadays. Snapshot tooling for etc relies on it.
+    (
+        6,
+        &[Split {
+            path: "etc",
+            nocow: &[],
+        }],
+    ),
+];
+
+// Whether `split` still has to be done. Nothing to do without the directory it goes into.
+fn split_pending(root: &Path, split: &Split) -> bool {
+    let path = root.join("@system").join(split.path);
+    path.parent().is_some_and(Path::exists) && !convert::converted(&path)
+}
+
+// The layouts after v3 with work left, see also rootfs-transition.
+fn pending_split_layouts(root: &Path) -> Vec<(u8, &'static [Split])> {
+    SPLIT_LAYOUTS
+        .into_iter()
+        .filter(|(_, splits)| splits.iter().any(|split| split_pending(root, split)))
+        .collect()
+}
+
+// Does all of `layouts` in one go, so that a v3 system does not sit through a migration on each of the next boots.
+fn run_split(
+    root: &Path,
+    policy: &Policy,
+    layouts: &[(u8, &[Split])],
+) -> Result<(), Box<dyn Error>> {
+    let (Some((first, _)), Some((to, _))) = (layouts.first(), layouts.last()) else {
+        return Ok(());
+    };
+    let (from, to) = (first - 1, *to);
+    let headline = tr(
+        "Migrating to v{to} rootfs. This will take a while.",
+        &[("to", &to)],
+    );
+    plymouth::begin_progress();
+    plymouth::status(&headline);
+    journal::started(from, to);
+
+    // An interrupted attempt leaves a layout that boots fine, finishing it can wait as well.
+    if policy.mode == Mode::Defer {
+        journal::skipped("Not migrating this boot (kde-linux.migrate=defer).");
+        return Ok(());
+    }
+
+    let splits: Vec<(u8, &Split)> = layouts
+        .iter()
+        .flat_map(|(layout, splits)| splits.iter().map(move |split| (*layout, split)))
+        .collect();
+    for (index, (layout, split)) in splits.iter().enumerate() {
+        let path = root.join("@system").join(split.path);
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if !path.parent().is_some_and(Path::exists) {
+            println!("{path:?} has nowhere to go, skipping");
+            continue;
+        }
+        plymouth::progress((index * 100 / splits.len()) as u8);
+        plymouth::status(&format!(
+            "{headline}\n{}",
+            tr("Moving /{dir}", &[("dir", &split.path)])
+        ));
+        let phase = journal::phase(&name);
+        convert::into_subvolume(
+            &path,
+            split.nocow,
+            &format!("v{layout}-{name}"),
+            &format!(
+                "@system/{} is untouched. The next boot discards {name}.subvoltmp and retries the migration.",
+                split.path
+            ),
+        )?;
+        phase.finish(&[("PATH", &path.display()), ("BYTES", &tree_size(&path))]);
+    }
+    plymouth::progress(100);
+
+    journal::finished(&format!("Migrated rootfs layout v{from} to v{to}"));
+    Ok(())
+}
+
+// The reverse of run_v3: fold the per-user subvolumes back into a single @system/home subvolume so that images
+// predating v3 can boot. Mirrors the staging and rename dance of run_v3 with its own staging names so that the two
+// never mistake each other's leftovers.
+fn rollback_v3(root: &Path) -> Result<(), Box<dyn Error>> {
+    let system = root.join("@system");
+    let system_home = root.join("@system/home");
+    let system_home_tmp = root.join("@system/home.v2tmp");
+    let system_home_old = root.join("@system/home.v2old");
+    journal::started(3, 2);
+    println!(
+        "Rolling back @system/home from regular directory with per-user subvolumes to a single subvolume"
+    );
+
+    if root.join("@system/home.v3old").exists() || root.join("@system/home.v3tmp").exists() {
+        return Err(Class::Inconsistent.error(
+            "A v3 migration was interrupted. Boot a v3 capable image to let it finish before \
+             rolling back.",
+        ));
+    }
+
+    // Rolling back under the feet of a running system loses whatever it writes to home meanwhile, and its next boot
+    // finds a layout it does not expect. ensure_unmounted refuses @system mounted anywhere but below `root`.
+    ensure_unmounted(&system)?;
+    for path in [&system_home, &system_home_tmp, &system_home_old] {
+        ensure_unmounted(path)?;
+    }
+
+    // The crash windows are those of run_v3: before the exchange only tmp needs deleting, after it tmp is the v3 home
+    // and still to be renamed to old, after that only old needs deleting. Home itself never goes missing.
+
+    // Crashed after exchange(tmp↔home) but before rename(tmp→old). tmp is only ever created as a subvolume, so a
+    // regular directory there is the v3 home and home already is the rolled back subvolume.
+    if system_home_tmp.exists() && !is_subvolume(&system_home_tmp).unwrap_or(true) {
+        if system_home_old.exists() {
+            return Err(Class::Inconsistent.error(format!(
+                "Both {system_home_tmp:?} and {system_home_old:?} exist and {system_home_tmp:?} is \
+                 a regular directory. Refusing to touch either."
+            )));
+        }
+        println!("Detected swapped v3 rollback: moving {system_home_tmp:?} to {system_home_old:?}");
+        fs::rename(&system_home_tmp, &system_home_old)?;
+        fsync_dir(&system).context(|| format!("Failed to sync {system:?}"))?;
+    }
+
+    // Clean up any leftover staging dirs from a previous failed run.
+    if system_home_old.exists() {
+        if !system_home.exists() {
+            // Nothing we do leaves this behind, somebody else moved home away.
+            return Err(Class::Inconsistent.error(format!(
+                "{system_home_old:?} exists but {system_home:?} is missing. Refusing to touch \
+                 {system_home_old:?}, it may be the only copy of the homes."
+            )));
+        } else if !is_subvolume(&system_home).unwrap_or(false) {
+            // Same reasoning as in run_v3, just inverted: home only becomes a subvolume again
+            // once the staging subvolume took its place.
+            return Err(Class::Inconsistent.error(format!(
+                "Both {system_home:?} and {system_home_old:?} exist and {system_home:?} is \
+                 still a regular directory. Refusing to touch either."
+            )));
+        } else {
+            // home is a subvolume, so a previous run completed and only failed to clean up.
+            println!("Cleaning up leftover {system_home_old:?} from previous run");
+            remove_tree(&system_home_old)?;
+        }
+    }
+
+    if system_home_tmp.exists() {
+        println!("Cleaning up leftover {system_home_tmp:?} from previous run");
+        DeleteSubvolumeOptions::new()
+            .recursive(true)
+            .delete(&system_home_tmp)
+            .context(|| format!("Failed to delete leftover {system_home_tmp:?}"))?;
+    }
+
+    if !system_home.exists() {
+        journal::skipped(&format!(
+            "{system_home:?} does not exist. Nothing to roll back."
+        ));
+        return Ok(());
+    }
+    if is_subvolume(&system_home).context(|| format!("Failed to stat {system_home:?}"))? {
+        journal::skipped(&format!(
+            "{system_home:?} is already a subvolume. Nothing to do."
+        ));
+        return Ok(());
+    }
+
+    let next_boot =
+        "@system/home is untouched. Run the rollback again to discard home.v2tmp and retry.";
+
+    // Stage into a sibling subvolume so that if we crash mid-way, @system/home is still the v3 directory.
+    CreateSubvolumeOptions::new()
+        .create(&system_home_tmp)
+        .context(|| format!("Failed to create subvolume {system_home_tmp:?}"))?;
+    fault::inject("rollback-after-create-tmp")?;
+    for entry in fs::read_dir(&system_home)? {
+        checkpoint(next_boot)?;
+        let entry = entry?;
+        fault::inject(&format!(
+            "rollback-before-copy-{}",
+            entry.file_name().to_string_lossy()
+        ))?;
+        let src = entry.path();
+        let file_type = entry.file_type()?;
+        let dst = system_home_tmp.join(entry.file_name());
+
+        if !file_type.is_dir() && !file_type.is_file() && !file_type.is_symlink() {
+            journal::warning(
+                &format!("Not copying {src:?}, unsupported file type {file_type:?}"),
+                &[("PATH", &src.display())],
+            );
+            continue;
+        }
+
+        // The user subvolume turns into a plain directory. Nested subvolumes inside it are copied as plain dirs
+        // first and then replaced with snapshots, same as run_v3 does.
+        println!("Copying {src:?} to {dst:?}");
+        let cp_result = cp()
+            .arg("--recursive")
+            .arg("--archive")
+            .arg("--reflink=auto")
+            .arg("--no-target-directory")
+            .arg(&src)
+            .arg(&dst)
+            .status()
+            .expect("Failed to copy home entry");
+        if !cp_result.success() {
+            return Err(Class::Copy.error(format!("Failed to copy {src:?} to {dst:?}")));
+        }
+
+        if file_type.is_dir() {
+            snapshot_nested_subvolumes(&src, &dst)?;
+        }
+    }
+
+    checkpoint(next_boot)?;
+    sync_fs(&system).context(|| format!("Failed to sync {system:?}"))?;
+    fault::inject("rollback-before-exchange")?;
+    ensure_unmounted(&system_home)?;
+    ensure_unmounted(&system_home_tmp)?;
+
+    println!("Exchanging {system_home_tmp:?} and {system_home:?}");
+    rename_exchange(&system_home_tmp, &system_home)
+        .context(|| format!("Failed to exchange {system_home_tmp:?} and {system_home:?}"))?;
+    fsync_dir(&system).context(|| format!("Failed to sync {system:?}"))?;
+    fault::inject("rollback-after-exchange")?;
+
+    println!("Renaming {system_home_tmp:?} to {system_home_old:?}");
+    fs::rename(&system_home_tmp, &system_home_old)?;
+    fsync_dir(&system).context(|| format!("Failed to sync {system:?}"))?;
+    fault::inject("rollback-after-rename-tmp-to-old")?;
+
+    // Only delete the per-user subvolumes once we know the old layout is in place
+    println!("Deleting old home directory {system_home_old:?}");
+    let _ = remove_tree(&system_home_old).inspect_err(|e| {
+        journal::warning(
+            &format!(
+                "The rollback succeeded but deleting the old directory failed: {e}. \
+                 {system_home_old:?} can be manually deleted."
+            ),
+            &[("PATH", &system_home_old.display())],
+        );
+    });
+    let _ = sync_fs(&system);
+
+    journal::finished(
+        "Rollback complete. Booting a v3 capable image will migrate @system/home forward again.",
+    );
+    Ok(())
+}
+
+fn usage(program: &str) {
+    println!("Usage: {program} [--output=human|jsonl] system_mount");
+    println!("       {program} [--output=human|jsonl] rollback system_mount");
+    println!("       {program} [--output=human|jsonl] user-subvolumes [--categories=LIST] [home…]");
+    println!(
+        "Migrates a legacy subvol (pre-May-2025) to v2 rootfs, v2 to v3, or v3 and later to v6 in one go."
+    );
+    println!(
+        "v4 to v6 move /var/lib/flatpak, /var/log, /var/cache, /var/tmp and /etc into subvolumes."
+    );
+    println!(
+        "rollback reverts a v3 @system/home to v2. Run it with @system not in use, e.g. from a live system."
+    );
+    println!(
+        "user-subvolumes makes the existing caches, VM image and game library directories in the homes"
+    );
+    println!(
+        "(default all in /home) nested subvolumes. VM images and games get copy-on-write disabled for files"
+    );
+    println!(
+        "created from then on. Homes whose user is logged in are skipped. LIST is all, none or some of"
+    );
+    println!("cache,vms,games, the default comes from kde-linux.migrate.user-subvolumes=.");
+    println!(
+        "--output=jsonl prints one JSON event per line (schema 1) on stdout instead. Human output goes to stderr then."
+    );
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
-    let args: Vec<String> = env::args().collect();
+    cancel::install_handlers()?;
+
+    let mut args: Vec<String> = env::args().collect();
+    if let Some(index) = args.iter().position(|arg| arg.starts_with("--output=")) {
+        match args.remove(index).trim_start_matches("--output=") {
+            "human" => {}
+            "jsonl" => events::enable()?,
+            other => {
+                usage(&args[0]);
+                return Err(format!("Unknown output format {other:?}").into());
+            }
+        }
+    }
     if args.len() < 2 {
-        println!("Usage: {} system_mount", args[0]);
-        println!("Migrates a legacy subvol (pre-May-2025) to v2 rootfs");
+        usage(&args[0]);
         return Err("Not enough arguments".into());
     }
 
+    if args[1] == "rollback" {
+        let Some(root) = args.get(2) else {
+            usage(&args[0]);
+            return Err("Not enough arguments".into());
+        };
+        journal::context("ROOT", root);
+        mountinfo::running_system();
+        return rollback_v3(Path::new(root)).inspect_err(|e| {
+            journal::failed(e.as_ref());
+            bundle::write(Path::new(root), e.as_ref());
+            help::show(e.as_ref());
+        });
+    }
+
+    if args[1] == "user-subvolumes" {
+        mountinfo::running_system();
+        let mut categories = None;
+        let mut homes = Vec::new();
+        for arg in &args[2..] {
+            if let Some(list) = arg.strip_prefix("--categories=") {
+                categories = Some(nested::parse(list)?);
+            } else {
+                homes.push(PathBuf::from(arg));
+            }
+        }
+        let categories = match categories {
+            Some(categories) => categories,
+            None => Policy::from_cmdline().user_subvolumes,
+        };
+        return nested::run(&homes, &categories).inspect_err(|e| {
+            journal::failed(e.as_ref());
+            help::show(e.as_ref());
+        });
+    }
+
     let root = Path::new(&args[1]);
     let system_path = root.join("@system");
+    let policy = Policy::from_cmdline();
+    i18n::init(root);
+    journal::context("ROOT", root.display());
+    journal::context("POLICY", policy.mode);
 
-    let result = if system_path.exists() {
-        println!("Migrating to v3 rootfs. This will take a while.");
-        run_v3(root)
+    // Waiting for an answer to a prompt counts as progress as well.
+    let keepalive = notify::keepalive();
+    let split_layouts = pending_split_layouts(root);
+    let result = if !system_path.exists() {
+        run(root, &policy)
+    } else if v3_pending(root) {
+        run_v3(root, &policy)
+    } else if !split_layouts.is_empty() {
+        run_split(root, &policy, &split_layouts)
     } else {
-        println!("Migrating to v2 rootfs. This will take a while.");
-        run(root)
+        journal::skipped("The rootfs layout is up to date. Nothing to do.");
+        Ok(())
     };
 
+    drop(keepalive);
+    if let Err(e) = &result {
+        journal::failed(e.as_ref());
+    }
+    report::write(root);
+
     match result {
         Ok(_) => {
+            plymouth::end_progress();
             // Reactivate in case we deactivated it earlier
-            let _ = Command::new("plymouth").arg("show-splash").status();
+            plymouth::show_splash();
+            notify::status("Migration complete");
             Ok(())
         }
         Err(e) => {
+            bundle::write(root, e.as_ref());
             // Quit plymouth if there was a fatal problem so the user can see the output
-            Command::new("plymouth")
-                .arg("quit")
-                .status()
-                .expect("failed to execute plymouth show-splash");
+            plymouth::quit();
+            help::show(e.as_ref());
+            notify::status(&format!("Migration failed: {e}"));
             Err(e)
         }
     }
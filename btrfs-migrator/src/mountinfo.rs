@@ -0,0 +1,222 @@
+// SPDX-License-Identifier: GPL-2.0-only OR GPL-3.0-only OR LicenseRef-KDE-Accepted-GPL
+// SPDX-FileCopyrightText: None
+
+// Nothing is supposed to be mounted inside the paths we rename and delete, but a leftover from an earlier failed boot
+// or a user's own mount generator may be. rename and subvolume deletion then fail with EBUSY at best, remove_dir_all
+// descends into the mount and deletes someone else's data at worst.
+//
+// In the initrd such mounts can only be leftovers and get unmounted. The subcommands that run on a running system see
+// the user's own mounts, a game library on a disk of its own say, and refuse instead, see running_system().
+
+use std::{
+    error::Error,
+    ffi::{CString, OsString},
+    fs, io,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::help::{Class, Context};
+
+static RUNNING_SYSTEM: AtomicBool = AtomicBool::new(false);
+
+struct Mount {
+    // major:minor of the filesystem, the same for every mount of it.
+    device: String,
+    // Path inside the filesystem that is mounted, for btrfs that is the subvolume path.
+    root: PathBuf,
+    mount_point: PathBuf,
+    fs_type: String,
+    source: String,
+}
+
+// Paths are escaped as octal: \040 for space, \011 tab, \012 newline, \134 backslash. fstab and crypttab use the
+// same escapes, see fstab.rs. Exactly three digits make an escape, anything else is kept as is. What they decode to
+// need not be UTF-8.
+pub fn unescape(field: &str) -> OsString {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if let Some(
+            &[
+                b'\\',
+                high @ b'0'..=b'3',
+                mid @ b'0'..=b'7',
+                low @ b'0'..=b'7',
+            ],
+        ) = bytes.get(i..i + 4)
+        {
+            out.push((high - b'0') << 6 | (mid - b'0') << 3 | (low - b'0'));
+            i += 4;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    OsString::from_vec(out)
+}
+
+// 36 35 98:0 /mnt1 /mnt2 rw,noatime master:1 - ext3 /dev/root rw,errors=continue
+fn parse_line(line: &str) -> Option<Mount> {
+    let (front, back) = line.split_once(" - ")?;
+    let front: Vec<&str> = front.split(' ').collect();
+    let back: Vec<&str> = back.split(' ').collect();
+    Some(Mount {
+        device: front.get(2)?.to_string(),
+        root: unescape(front.get(3)?).into(),
+        mount_point: unescape(front.get(4)?).into(),
+        fs_type: back.first()?.to_string(),
+        source: back.get(1).map(|s| s.to_string()).unwrap_or_default(),
+    })
+}
+
+fn mounts() -> io::Result<Vec<Mount>> {
+    Ok(fs::read_to_string("/proc/self/mountinfo")?
+        .lines()
+        .filter_map(parse_line)
+        .collect())
+}
+
+fn umount(path: &Path) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::umount2(path.as_ptr(), 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// We are not in the initrd, whatever is mounted belongs to someone.
+pub fn running_system() {
+    RUNNING_SYSTEM.store(true, Ordering::Relaxed);
+}
+
+// The mount point at or inside `path`, if any.
+pub fn mounted_inside(path: &Path) -> Result<Option<PathBuf>, Box<dyn Error>> {
+    let Ok(path) = fs::canonicalize(path) else {
+        return Ok(None);
+    };
+    Ok(mounts()
+        .context(|| "Failed to read /proc/self/mountinfo")?
+        .into_iter()
+        .find(|m| m.mount_point.starts_with(&path))
+        .map(|m| m.mount_point))
+}
+
+// Make sure `path` is safe to rename or delete. Mounts inside it are unmounted, deepest first, as in the initrd they
+// can only be leftovers: at this point in the boot nothing else has any business in there. Should that fail, should
+// we run on a running system, or should the subvolume at `path` be mounted somewhere else entirely, we refuse.
+pub fn ensure_unmounted(path: &Path) -> Result<(), Box<dyn Error>> {
+    let Ok(path) = fs::canonicalize(path) else {
+        return Ok(()); // Does not exist, nothing can be mounted there.
+    };
+    let read_mounts = || mounts().context(|| "Failed to read /proc/self/mountinfo");
+
+    let mounts = read_mounts()?;
+    let mut inside: Vec<&Mount> = mounts
+        .iter()
+        .filter(|m| m.mount_point.starts_with(&path))
+        .collect();
+    inside.sort_by_key(|m| std::cmp::Reverse(m.mount_point.components().count()));
+    if RUNNING_SYSTEM.load(Ordering::Relaxed)
+        && let Some(mount) = inside.last()
+    {
+        return Err(Class::Busy.error(format!(
+            "{:?} ({} from {}) is mounted inside {path:?}. Refusing to touch it while it is in use, unmount it and \
+             run again.",
+            mount.mount_point, mount.fs_type, mount.source
+        )));
+    }
+    for mount in inside {
+        println!(
+            "Unmounting {:?} ({} from {}) inside {path:?}",
+            mount.mount_point, mount.fs_type, mount.source
+        );
+        umount(&mount.mount_point).map_err(|e| {
+            Class::Busy.caused_by(
+                format!(
+                    "{:?} ({} from {}) is mounted inside {path:?} and could not be unmounted: {e}. \
+                     Remove whatever mounts it and reboot to retry.",
+                    mount.mount_point, mount.fs_type, mount.source
+                ),
+                e,
+            )
+        })?;
+    }
+
+    let mounts = read_mounts()?;
+    // The mount we reach `path` through, and with it where `path` lives inside that filesystem.
+    let Some(host) = mounts
+        .iter()
+        .filter(|m| path.starts_with(&m.mount_point))
+        .max_by_key(|m| m.mount_point.components().count())
+    else {
+        return Ok(());
+    };
+    let in_fs = host.root.join(path.strip_prefix(&host.mount_point)?);
+    if let Some(elsewhere) = mounts.iter().find(|m| {
+        m.device == host.device && m.root.starts_with(&in_fs) && !m.mount_point.starts_with(&path)
+    }) {
+        return Err(Class::Busy.error(format!(
+            "{path:?} is also mounted at {:?} ({} from {}). Refusing to touch it while it is in use.",
+            elsewhere.mount_point, elsewhere.fs_type, elsewhere.source
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escaped_paths() {
+        assert_eq!(unescape("/mnt/my\\040disk"), "/mnt/my disk");
+        assert_eq!(unescape("/a\\011b\\012c\\134d"), "/a\tb\nc\\d");
+        // Not octal, or not three digits, stays as is.
+        assert_eq!(unescape("/a\\089"), "/a\\089");
+        assert_eq!(unescape("/a\\04"), "/a\\04");
+        assert_eq!(unescape("/a\\"), "/a\\");
+        assert_eq!(unescape("/a\\777"), "/a\\777");
+        assert_eq!(unescape("/a\\+12"), "/a\\+12");
+        assert_eq!(unescape("/a\\-12"), "/a\\-12");
+        // Bytes, not characters.
+        assert_eq!(unescape("/b\\303\\244r"), "/bär");
+        assert_eq!(unescape("/\\377").as_bytes(), b"/\xff");
+    }
+
+    #[test]
+    fn optional_fields() {
+        let mount = parse_line(
+            "36 35 98:0 /mnt1 /mnt2 rw,noatime master:1 shared:2 - ext3 /dev/root rw,errors=continue",
+        )
+        .unwrap();
+        assert_eq!(mount.device, "98:0");
+        assert_eq!(mount.root, Path::new("/mnt1"));
+        assert_eq!(mount.mount_point, Path::new("/mnt2"));
+        assert_eq!(mount.fs_type, "ext3");
+        assert_eq!(mount.source, "/dev/root");
+
+        let mount =
+            parse_line("40 1 0:33 /@system/home /sysroot/home rw - btrfs /dev/vda2 rw").unwrap();
+        assert_eq!(mount.root, Path::new("/@system/home"));
+        assert_eq!(mount.fs_type, "btrfs");
+    }
+
+    #[test]
+    fn separator() {
+        // Escaped, a mount point containing " - " does not split the line early.
+        let mount =
+            parse_line("41 1 0:40 / /mnt/a\\040-\\040b rw shared:5 - tmpfs tmpfs rw,size=1024k")
+                .unwrap();
+        assert_eq!(mount.mount_point, Path::new("/mnt/a - b"));
+        assert_eq!(mount.fs_type, "tmpfs");
+        assert_eq!(mount.source, "tmpfs");
+
+        assert!(parse_line("36 35 98:0 /mnt1 /mnt2 rw ext3 /dev/root rw").is_none());
+        assert!(parse_line("36 35 98:0 - ext3 /dev/root rw").is_none());
+        assert!(parse_line("").is_none());
+    }
+}
@@ -0,0 +1,223 @@
+// SPDX-License-Identifier: GPL-2.0-only OR GPL-3.0-only OR LicenseRef-KDE-Accepted-GPL
+// SPDX-FileCopyrightText: None
+
+// Well-known places in a home that have no business in its snapshots: caches, VM images and game libraries. Each
+// becomes a nested subvolume of the user subvolume, which snapshots of it leave out. Only directories that exist are
+// converted, see convert.rs. Nothing gets created for applications a user never ran. VM images and games are
+// rewritten in place all the time, so their directories get copy-on-write disabled. That only affects files created
+// in them afterwards. The files already there stay copy-on-write, turning it off for them means copying them in full.
+//
+// Which categories is up to the policy, see policy.rs. run_v3 does it for every user it migrates, the user-subvolumes
+// subcommand for the users of an existing system.
+
+use std::{
+    error::Error,
+    fmt, fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    attributes::set_nocow,
+    convert,
+    help::{Class, Context},
+    journal, mountinfo, subvolume,
+};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Category {
+    Cache,
+    Vms,
+    Games,
+}
+
+pub const ALL: [Category; 3] = [Category::Cache, Category::Vms, Category::Games];
+
+impl fmt::Display for Category {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Category::Cache => "cache",
+            Category::Vms => "vms",
+            Category::Games => "games",
+        })
+    }
+}
+
+// all, none or a comma separated list of categories.
+pub fn parse(list: &str) -> Result<Vec<Category>, Box<dyn Error>> {
+    match list {
+        "all" => return Ok(ALL.to_vec()),
+        "none" => return Ok(Vec::new()),
+        _ => {}
+    }
+    list.split(',')
+        .map(|name| {
+            ALL.into_iter()
+                .find(|category| category.to_string() == name)
+                .ok_or_else(|| {
+                    format!("invalid category {name:?}, expected all, none or a list of cache, vms and games")
+                        .into()
+                })
+        })
+        .collect()
+}
+
+// The inverse of parse().
+pub fn list(categories: &[Category]) -> String {
+    if categories.is_empty() {
+        return "none".to_string();
+    }
+    categories
+        .iter()
+        .map(Category::to_string)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+// Relative to the home, and whether to disable copy-on-write.
+const PATHS: [(Category, &str, bool); 7] = [
+    (Category::Cache, ".cache", false),
+    (Category::Vms, ".local/share/libvirt/images", true),
+    (Category::Vms, ".local/share/gnome-boxes/images", true),
+    (
+        Category::Vms,
+        ".var/app/org.gnome.Boxes/data/gnome-boxes/images",
+        true,
+    ),
+    (Category::Vms, "VirtualBox VMs", true),
+    (Category::Games, ".local/share/Steam/steamapps", true),
+    (
+        Category::Games,
+        ".var/app/com.valvesoftware.Steam/.local/share/Steam/steamapps",
+        true,
+    ),
+];
+
+// Whether `path` is a directory we may turn into a subvolume: really inside `home` rather than behind a symlink, and
+// neither a mount, e.g. a Steam library on another disk, nor inside one. Like a mount, a subvolume has a st_dev of its
+// own. It can only be one we made on an earlier run, then.
+fn inside(home: &Path, path: &Path) -> Result<bool, Box<dyn Error>> {
+    let Some(parent) = path.parent() else {
+        return Ok(false);
+    };
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return Ok(false);
+    };
+    if !metadata.is_dir() {
+        return Ok(false);
+    }
+    let canonical = parent.canonicalize()?;
+    if !canonical.starts_with(home.canonicalize()?) {
+        return Ok(false);
+    }
+    let dev = fs::metadata(home)?.dev();
+    if fs::metadata(&canonical)?.dev() != dev {
+        return Ok(false);
+    }
+    if let Some(mount_point) = mountinfo::mounted_inside(path)? {
+        println!("Leaving {path:?} alone, {mount_point:?} is mounted there");
+        return Ok(false);
+    }
+    Ok(metadata.dev() == dev || subvolume(path)?)
+}
+
+// Applies `categories` to the user subvolume `home`. `fault` prefixes the fault injection points. Returns the paths
+// that are nested subvolumes now, copy-on-write disabled for new files where PATHS says so.
+pub fn apply(
+    home: &Path,
+    categories: &[Category],
+    fault: &str,
+    next_boot: &str,
+) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut done = Vec::new();
+    for (category, relative, nocow) in PATHS {
+        if !categories.contains(&category) {
+            continue;
+        }
+        let path = home.join(relative);
+        if !inside(home, &path)? {
+            continue;
+        }
+        let name = relative.replace(['/', ' '], "-");
+        convert::into_subvolume(&path, &[], &format!("{fault}-{name}"), next_boot)?;
+        if nocow {
+            set_nocow(&path).context(|| format!("Failed to disable copy-on-write for {path:?}"))?;
+        }
+        done.push(path);
+    }
+    Ok(done)
+}
+
+// Whether anything runs as `uid`. Converting a directory under the feet of its user loses whatever they write to it
+// meanwhile.
+fn logged_in(uid: u32) -> Result<bool, Box<dyn Error>> {
+    for entry in fs::read_dir("/proc").context(|| "Failed to read /proc")? {
+        let entry = entry?;
+        if !entry
+            .file_name()
+            .to_string_lossy()
+            .bytes()
+            .all(|b| b.is_ascii_digit())
+        {
+            continue;
+        }
+        // Gone already, fine.
+        if entry.metadata().is_ok_and(|metadata| metadata.uid() == uid) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// The user-subvolumes subcommand: apply() on a running system, for `homes` or every home in /home.
+pub fn run(homes: &[PathBuf], categories: &[Category]) -> Result<(), Box<dyn Error>> {
+    let homes = if homes.is_empty() {
+        let mut homes = Vec::new();
+        for entry in fs::read_dir("/home").context(|| "Failed to read /home")? {
+            let entry = entry?;
+            // lstat based, a symlinked home is none of our business.
+            if entry.file_type()?.is_dir() {
+                homes.push(entry.path());
+            }
+        }
+        homes.sort();
+        homes
+    } else {
+        homes.to_vec()
+    };
+    let next_boot =
+        "Whatever was converted already stays a subvolume. Run it again to do the rest.";
+    let mut skipped = Vec::new();
+    for home in &homes {
+        let uid = fs::metadata(home)
+            .context(|| format!("Failed to stat {home:?}"))?
+            .uid();
+        // Includes whoever runs us from their own session.
+        if logged_in(uid)? {
+            journal::warning(
+                &format!(
+                    "Skipping {home:?}, user {uid} owning it is logged in. Log them out and run again."
+                ),
+                &[("PATH", &home.display())],
+            );
+            skipped.push(home);
+            continue;
+        }
+        let phase = journal::phase(&format!(
+            "user-subvolumes-{}",
+            home.file_name().unwrap_or_default().to_string_lossy()
+        ));
+        let done = apply(home, categories, "user-subvolumes", next_boot)?;
+        for path in &done {
+            println!("{path:?} is a subvolume");
+        }
+        phase.finish(&[("PATH", &home.display()), ("COUNT", &done.len())]);
+    }
+    if !skipped.is_empty() {
+        return Err(Class::Busy.error(format!(
+            "Skipped {skipped:?}, their users are logged in. Log them out and run again."
+        )));
+    }
+    journal::finished("The user subvolumes are in place.");
+    Ok(())
+}
@@ -0,0 +1,109 @@
+// SPDX-License-Identifier: GPL-2.0-only OR GPL-3.0-only OR LicenseRef-KDE-Accepted-GPL
+// SPDX-FileCopyrightText: None
+
+// sd_notify(3) for the rootfs transition unit, so `systemctl status` says what we are doing and a long copy does not
+// run into the start timeout. We are a child of the unit's main process, which is why the unit needs
+// NotifyAccess=all. It is a oneshot, which has no start timeout unless it sets TimeoutStartSec=, see
+// kde-linux-mount-generator. Nor does a oneshot have a use for READY=1 or WATCHDOG=1: it is ready when it exits, and
+// the watchdog only runs once a unit is. Without NOTIFY_SOCKET, i.e. when run by hand, all of this does nothing.
+
+use std::{
+    env,
+    ffi::OsString,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::{ffi::OsStringExt, net::SocketAddr, net::UnixDatagram},
+    },
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, RecvTimeoutError, Sender},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+// Bumped whenever the main thread gets somewhere, see progress().
+static PROGRESS: AtomicU64 = AtomicU64::new(0);
+
+// How long the main thread may go without progress() before the keepalive gives up on it. Generous, a single cp of a
+// large home with reflinks unavailable takes a while.
+const STALL: Duration = Duration::from_secs(30 * 60);
+
+// How often to extend the start timeout.
+const INTERVAL: Duration = Duration::from_secs(10);
+
+fn send(state: &str) {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let path = path.into_vec();
+    // A leading @ denotes an abstract socket.
+    let addr = match path.strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name),
+        None => SocketAddr::from_pathname(OsString::from_vec(path)),
+    };
+    let result =
+        addr.and_then(|addr| UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr));
+    // A broken notify socket is no reason to stop migrating.
+    if let Err(e) = result {
+        eprintln!("Failed to notify systemd: {e}");
+    }
+}
+
+// One line, systemd shows it next to the unit in `systemctl status`.
+pub fn status(text: &str) {
+    progress();
+    send(&format!("STATUS={}", text.replace('\n', ": ")));
+}
+
+// The main thread is not stuck. Called at every status change and cancellation checkpoint.
+pub fn progress() {
+    PROGRESS.fetch_add(1, Ordering::Relaxed);
+}
+
+// Keeps extending the start timeout from a thread while the main thread is busy with a copy or sync that can take many
+// minutes. Only as long as it reports progress() every STALL, a hung cp or ioctl is what the timeout is there for.
+// Stops when dropped.
+pub struct Keepalive {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+pub fn keepalive() -> Keepalive {
+    let (stop, stopped) = mpsc::channel::<()>();
+    let thread = thread::spawn(move || {
+        let mut seen = PROGRESS.load(Ordering::Relaxed);
+        let mut since = Instant::now();
+        loop {
+            let current = PROGRESS.load(Ordering::Relaxed);
+            if current != seen {
+                seen = current;
+                since = Instant::now();
+            }
+            if since.elapsed() < STALL {
+                // Give ourselves a generous margin over the next check-in.
+                send(&format!(
+                    "EXTEND_TIMEOUT_USEC={}",
+                    (INTERVAL * 3).as_micros()
+                ));
+            }
+            match stopped.recv_timeout(INTERVAL) {
+                Err(RecvTimeoutError::Timeout) => continue,
+                Ok(()) | Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    });
+    Keepalive {
+        stop: Some(stop),
+        thread: Some(thread),
+    }
+}
+
+impl Drop for Keepalive {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,239 @@
+// SPDX-License-Identifier: GPL-2.0-only OR GPL-3.0-only OR LicenseRef-KDE-Accepted-GPL
+// SPDX-FileCopyrightText: None
+
+// The v1 etc and var as the old system saw them: the rootfs directory with its overlay on top, read-only. Mounted with
+// the new mount API, see fsopen(2), so that every directory is a parameter of its own. A mount(8) option string has no
+// way to carry commas, colons or arbitrary bytes in paths. When the kernel refuses, the fs-context log says why and
+// that goes into our error. lowerdir+ only exists since Linux 6.8, before that lowerdir with its special characters
+// escaped has to do.
+
+use std::{
+    error::Error,
+    ffi::CString,
+    io,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::ffi::OsStrExt,
+    },
+    path::Path,
+    ptr,
+};
+
+use crate::help::Class;
+
+// linux/mount.h, libc does not have them.
+const FSOPEN_CLOEXEC: libc::c_uint = 0x1;
+const FSCONFIG_SET_FLAG: libc::c_uint = 0;
+const FSCONFIG_SET_STRING: libc::c_uint = 1;
+const FSCONFIG_CMD_CREATE: libc::c_uint = 6;
+const FSMOUNT_CLOEXEC: libc::c_uint = 0x1;
+const MOUNT_ATTR_RDONLY: libc::c_uint = 0x1;
+const MOVE_MOUNT_F_EMPTY_PATH: libc::c_uint = 0x4;
+
+// For the syscalls that return a new file descriptor.
+fn owned_fd(ret: libc::c_long) -> io::Result<OwnedFd> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(ret as libc::c_int) })
+}
+
+fn check(ret: libc::c_long) -> io::Result<()> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+struct Context {
+    fd: OwnedFd,
+}
+
+impl Context {
+    fn open(fs_type: &str) -> io::Result<Self> {
+        let fs_type = CString::new(fs_type)?;
+        let fd =
+            owned_fd(unsafe { libc::syscall(libc::SYS_fsopen, fs_type.as_ptr(), FSOPEN_CLOEXEC) })?;
+        Ok(Context { fd })
+    }
+
+    fn set_string(&self, key: &str, value: &[u8]) -> io::Result<()> {
+        let key = CString::new(key)?;
+        let value = CString::new(value)?;
+        check(unsafe {
+            libc::syscall(
+                libc::SYS_fsconfig,
+                self.fd.as_raw_fd(),
+                FSCONFIG_SET_STRING,
+                key.as_ptr(),
+                value.as_ptr(),
+                0,
+            )
+        })
+    }
+
+    fn set_flag(&self, key: &str) -> io::Result<()> {
+        let key = CString::new(key)?;
+        check(unsafe {
+            libc::syscall(
+                libc::SYS_fsconfig,
+                self.fd.as_raw_fd(),
+                FSCONFIG_SET_FLAG,
+                key.as_ptr(),
+                ptr::null::<libc::c_char>(),
+                0,
+            )
+        })
+    }
+
+    fn create(&self) -> io::Result<()> {
+        check(unsafe {
+            libc::syscall(
+                libc::SYS_fsconfig,
+                self.fd.as_raw_fd(),
+                FSCONFIG_CMD_CREATE,
+                ptr::null::<libc::c_char>(),
+                ptr::null::<libc::c_char>(),
+                0,
+            )
+        })
+    }
+
+    fn mount(&self) -> io::Result<OwnedFd> {
+        owned_fd(unsafe {
+            libc::syscall(
+                libc::SYS_fsmount,
+                self.fd.as_raw_fd(),
+                FSMOUNT_CLOEXEC,
+                MOUNT_ATTR_RDONLY,
+            )
+        })
+    }
+
+    // What the kernel had to say, one message per read. They start with "e " for errors, "w " for warnings and "i "
+    // for information.
+    fn log(&self) -> Vec<String> {
+        let mut messages = Vec::new();
+        let mut buffer = [0u8; 4096];
+        loop {
+            let read = unsafe {
+                libc::read(
+                    self.fd.as_raw_fd(),
+                    buffer.as_mut_ptr().cast(),
+                    buffer.len(),
+                )
+            };
+            if read <= 0 {
+                // ENODATA once the log is drained.
+                return messages;
+            }
+            let message = String::from_utf8_lossy(&buffer[..read as usize]);
+            messages.push(message.trim_end().to_string());
+        }
+    }
+}
+
+fn attach(mount: &OwnedFd, target: &Path) -> io::Result<()> {
+    let empty = CString::default();
+    let target = CString::new(target.as_os_str().as_bytes())?;
+    check(unsafe {
+        libc::syscall(
+            libc::SYS_move_mount,
+            mount.as_raw_fd(),
+            empty.as_ptr(),
+            libc::AT_FDCWD,
+            target.as_ptr(),
+            MOVE_MOUNT_F_EMPTY_PATH,
+        )
+    })
+}
+
+// For lowerdir, which splits at colons and, through the option string of kernels before 6.5, at commas.
+fn escape_lowerdir(path: &Path) -> Vec<u8> {
+    let mut escaped = Vec::new();
+    for byte in path.as_os_str().as_bytes() {
+        if [b'\\', b':', b','].contains(byte) {
+            escaped.push(b'\\');
+        }
+        escaped.push(*byte);
+    }
+    escaped
+}
+
+// One attempt at mounting. `plus` picks lowerdir+ over lowerdir. Errors come with what the fs-context log has to say
+// about them.
+fn try_mount(
+    plus: bool,
+    lower: &Path,
+    upper: &Path,
+    work: &Path,
+    target: &Path,
+) -> Result<(), (io::Error, Vec<String>)> {
+    let context = Context::open("overlay").map_err(|e| (e, Vec::new()))?;
+    let result = (|| {
+        if plus {
+            // Takes a single directory as is.
+            context.set_string("lowerdir+", lower.as_os_str().as_bytes())?;
+        } else {
+            context.set_string("lowerdir", &escape_lowerdir(lower))?;
+        }
+        context.set_string("upperdir", upper.as_os_str().as_bytes())?;
+        context.set_string("workdir", work.as_os_str().as_bytes())?;
+        context.set_string("index", b"off")?;
+        context.set_string("metacopy", b"off")?;
+        context.set_flag("ro")?;
+        context.create()?;
+        let mount = context.mount()?;
+        attach(&mount, target)
+    })();
+
+    let log = context.log();
+    for message in &log {
+        println!("overlay: {message}");
+    }
+    result.map_err(|e| {
+        let errors = log
+            .iter()
+            .filter_map(|message| message.strip_prefix("e "))
+            .map(str::to_string)
+            .collect();
+        (e, errors)
+    })
+}
+
+// Mount `lower` with `upper` on top at `target`, read-only.
+pub fn mount(lower: &Path, upper: &Path, work: &Path, target: &Path) -> Result<(), Box<dyn Error>> {
+    println!("Mounting overlay of {upper:?} on {lower:?} at {target:?}");
+    let result = match try_mount(true, lower, upper, work, target) {
+        // Unknown parameter, depending on the kernel already at fsconfig or only at create.
+        Err((e, _)) if e.raw_os_error() == Some(libc::EINVAL) => {
+            println!("Retrying with lowerdir, the kernel may not know lowerdir+: {e}");
+            try_mount(false, lower, upper, work, target)
+        }
+        result => result,
+    };
+    result.map_err(|(e, errors)| {
+        let detail = if errors.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", errors.join("; "))
+        };
+        Class::Mount.error(format!(
+            "Failed to mount the overlay at {target:?}: {e}{detail}"
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lowerdir_escaping() {
+        assert_eq!(
+            escape_lowerdir(Path::new("/sysroot/@kde-linux_1")),
+            b"/sysroot/@kde-linux_1"
+        );
+        assert_eq!(escape_lowerdir(Path::new("/a:b,c\\d")), b"/a\\:b\\,c\\\\d");
+    }
+}
@@ -0,0 +1,249 @@
+// SPDX-License-Identifier: GPL-2.0-only OR GPL-3.0-only OR LicenseRef-KDE-Accepted-GPL
+// SPDX-FileCopyrightText: None
+
+// Minimal client for plymouthd's boot protocol, see ply-boot-protocol.h. A request is the command character, then
+// either a NUL, or \002, the argument length including its NUL, and the argument. The daemon answers with ACK or
+// NAK, questions with ANSWER, a big endian u32 length and the answer. When plymouthd is not running, or stops
+// answering, everything goes to the console instead.
+
+use std::{
+    error::Error,
+    io::{self, Read, Write},
+    os::{
+        fd::AsFd,
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixStream},
+    },
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+
+use crate::{
+    cancel::{checkpoint, readable},
+    events, notify,
+};
+
+const SOCKETS: [&[u8]; 2] = [b"/org/freedesktop/plymouthd", b"/ply-boot-protocol"];
+
+const CHANGE_MODE: u8 = b'C';
+const SYSTEM_UPDATE: u8 = b'u';
+const QUIT: u8 = b'Q';
+const SHOW_MESSAGE: u8 = b'M';
+const HIDE_MESSAGE: u8 = b'm';
+const PROGRESS_PAUSE: u8 = b'A';
+const PROGRESS_UNPAUSE: u8 = b'a';
+const SHOW_SPLASH: u8 = b'$';
+const HIDE_SPLASH: u8 = b'H';
+const KEYSTROKE: u8 = b'K';
+
+const ACK: u8 = 0x06;
+const ANSWER: u8 = 0x02;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Client {
+    stream: Option<UnixStream>,
+    // The status currently on screen, so it can be taken down before showing the next one.
+    message: Option<String>,
+    progress: Option<u8>,
+}
+
+static CLIENT: LazyLock<Mutex<Client>> = LazyLock::new(|| {
+    Mutex::new(Client {
+        stream: connect(),
+        message: None,
+        progress: None,
+    })
+});
+
+fn connect() -> Option<UnixStream> {
+    SOCKETS.iter().find_map(|name| {
+        let addr = SocketAddr::from_abstract_name(name).ok()?;
+        let stream = UnixStream::connect_addr(&addr).ok()?;
+        // Never hang the migration on a wedged splash.
+        stream.set_read_timeout(Some(TIMEOUT)).ok()?;
+        stream.set_write_timeout(Some(TIMEOUT)).ok()?;
+        Some(stream)
+    })
+}
+
+fn write_request(stream: &mut UnixStream, command: u8, argument: Option<&str>) -> io::Result<()> {
+    let mut buffer = vec![command];
+    match argument {
+        Some(argument) => {
+            // The length is a single byte that includes the NUL.
+            let mut end = argument.len().min(u8::MAX as usize - 1);
+            while !argument.is_char_boundary(end) {
+                end -= 1;
+            }
+            buffer.push(0x02);
+            buffer.push((end + 1) as u8);
+            buffer.extend_from_slice(&argument.as_bytes()[..end]);
+            buffer.push(0);
+        }
+        None => buffer.push(0),
+    }
+    stream.write_all(&buffer)
+}
+
+fn read_reply(stream: &mut UnixStream) -> io::Result<u8> {
+    let mut reply = [0u8; 1];
+    stream.read_exact(&mut reply)?;
+    Ok(reply[0])
+}
+
+fn send(stream: &mut UnixStream, command: u8, argument: Option<&str>) -> io::Result<u8> {
+    write_request(stream, command, argument)?;
+    read_reply(stream)
+}
+
+fn read_answer(stream: &mut UnixStream) -> io::Result<String> {
+    let mut size = [0u8; 4];
+    stream.read_exact(&mut size)?;
+    let mut answer = vec![0u8; u32::from_be_bytes(size) as usize];
+    stream.read_exact(&mut answer)?;
+    Ok(String::from_utf8_lossy(&answer).into_owned())
+}
+
+impl Client {
+    fn lost(&mut self, error: io::Error) {
+        eprintln!("Lost connection to plymouthd, continuing on the console: {error}");
+        self.stream = None;
+    }
+
+    // Returns whether plymouthd took the request. Any protocol trouble drops the connection for good.
+    fn request(&mut self, command: u8, argument: Option<&str>) -> bool {
+        let Some(stream) = self.stream.as_mut() else {
+            return false;
+        };
+        match send(stream, command, argument) {
+            Ok(reply) => reply == ACK,
+            Err(e) => {
+                self.lost(e);
+                false
+            }
+        }
+    }
+
+    // Waits until one of `keys` is pressed, there is no telling how long the user takes. SIGINT and SIGTERM still
+    // stop us, see cancel.rs.
+    fn watch_keystroke(&mut self, keys: &str) -> Result<Option<String>, Box<dyn Error>> {
+        let Some(stream) = self.stream.as_mut() else {
+            return Ok(None);
+        };
+        if let Err(e) = write_request(stream, KEYSTROKE, Some(keys)) {
+            self.lost(e);
+            return Ok(None);
+        }
+        loop {
+            if let Err(e) = checkpoint("Nothing was migrated. The next boot asks again.") {
+                // The answer would arrive in the middle of whatever we send next.
+                self.stream = None;
+                return Err(e);
+            }
+            match readable(stream.as_fd()) {
+                Ok(true) => break,
+                Ok(false) => continue,
+                Err(e) => {
+                    self.lost(e);
+                    return Ok(None);
+                }
+            }
+        }
+        let result = read_reply(stream).and_then(|reply| match reply {
+            ANSWER => read_answer(stream).map(Some),
+            _ => Ok(None),
+        });
+        Ok(result.unwrap_or_else(|e| {
+            self.lost(e);
+            None
+        }))
+    }
+}
+
+// Show `text` in place of the previous status. May span multiple lines. Also becomes the unit's status in systemd.
+pub fn status(text: &str) {
+    println!("{text}");
+    notify::status(text);
+    events::status(text);
+    let mut client = CLIENT.lock().unwrap();
+    if let Some(previous) = client.message.take() {
+        client.request(HIDE_MESSAGE, Some(&previous));
+    }
+    if client.request(SHOW_MESSAGE, Some(text)) {
+        client.message = Some(text.to_string());
+    }
+}
+
+// Ask `question` on the splash and wait for y or n. None when there is no splash to ask on, an error when cancelled
+// while waiting.
+pub fn ask_yes_no(question: &str) -> Result<Option<bool>, Box<dyn Error>> {
+    let mut client = CLIENT.lock().unwrap();
+    let previous = client.message.take();
+    if let Some(previous) = &previous {
+        client.request(HIDE_MESSAGE, Some(previous));
+    }
+    if !client.request(SHOW_MESSAGE, Some(question)) {
+        return Ok(None);
+    }
+    let key = client.watch_keystroke("yYnN");
+    client.request(HIDE_MESSAGE, Some(question));
+    if let Some(previous) = previous
+        && client.request(SHOW_MESSAGE, Some(&previous))
+    {
+        client.message = Some(previous);
+    }
+    Ok(key?.map(|key| key.eq_ignore_ascii_case("y")))
+}
+
+// Switch the splash into its progress bar mode. The theme titles it "Upgrading System...".
+pub fn begin_progress() {
+    let mut client = CLIENT.lock().unwrap();
+    client.request(CHANGE_MODE, Some("system-upgrade"));
+    client.progress = None;
+}
+
+pub fn progress(percent: u8) {
+    let percent = percent.min(100);
+    let mut client = CLIENT.lock().unwrap();
+    if client.progress == Some(percent) {
+        return;
+    }
+    client.progress = Some(percent);
+    events::progress(percent);
+    if !client.request(SYSTEM_UPDATE, Some(&percent.to_string())) {
+        println!("Progress: {percent}%");
+    }
+}
+
+// Back to the regular boot splash.
+pub fn end_progress() {
+    let mut client = CLIENT.lock().unwrap();
+    if let Some(previous) = client.message.take() {
+        client.request(HIDE_MESSAGE, Some(&previous));
+    }
+    client.request(CHANGE_MODE, Some("boot-up"));
+}
+
+pub fn pause_progress() {
+    CLIENT.lock().unwrap().request(PROGRESS_PAUSE, None);
+}
+
+pub fn unpause_progress() {
+    CLIENT.lock().unwrap().request(PROGRESS_UNPAUSE, None);
+}
+
+pub fn show_splash() {
+    CLIENT.lock().unwrap().request(SHOW_SPLASH, None);
+}
+
+pub fn hide_splash() {
+    CLIENT.lock().unwrap().request(HIDE_SPLASH, None);
+}
+
+// Quit without retaining the splash, so whatever we printed is visible.
+pub fn quit() {
+    let mut client = CLIENT.lock().unwrap();
+    client.request(QUIT, Some(""));
+    client.stream = None;
+}
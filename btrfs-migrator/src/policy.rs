@@ -0,0 +1,179 @@
+// SPDX-License-Identifier: GPL-2.0-only OR GPL-3.0-only OR LicenseRef-KDE-Accepted-GPL
+// SPDX-FileCopyrightText: None
+
+// What to do when nobody is around to answer questions, i.e. unattended fleets and openQA. Set on the kernel command
+// line:
+//   kde-linux.migrate=ask|auto|abort|defer
+//     ask    ask questions (the default)
+//     auto   answer every question with yes
+//     abort  fail the migration instead of asking
+//     defer  do not migrate this boot
+//   kde-linux.migrate.keep-snapshots=yes|no
+//     keep the pre-migration state around as read-only snapshot instead of deleting it (default no)
+//   kde-linux.migrate.user-subvolumes=all|none|<list>
+//     which of cache, vms and games get nested subvolumes in the user homes, comma separated (default all)
+//     see nested.rs
+// A typo in any of them must not fail the boot. It is warned about and the default used for that option instead.
+
+use std::{fmt, fs};
+
+use crate::{
+    journal,
+    nested::{self, Category},
+};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Mode {
+    Ask,
+    Auto,
+    Abort,
+    Defer,
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Mode::Ask => "ask",
+            Mode::Auto => "auto",
+            Mode::Abort => "abort",
+            Mode::Defer => "defer",
+        })
+    }
+}
+
+pub struct Policy {
+    pub mode: Mode,
+    pub keep_snapshots: bool,
+    pub user_subvolumes: Vec<Category>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            mode: Mode::Ask,
+            keep_snapshots: false,
+            user_subvolumes: nested::ALL.to_vec(),
+        }
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "1" | "yes" | "true" | "on" => Some(true),
+        "0" | "no" | "false" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+impl Policy {
+    // Along with a warning for every option that could not be parsed.
+    fn parse(cmdline: &str) -> (Self, Vec<String>) {
+        let mut policy = Policy::default();
+        let mut warnings = Vec::new();
+        // Later occurrences win, same as for systemd's own options.
+        for word in cmdline.split_whitespace() {
+            if let Some(mode) = word.strip_prefix("kde-linux.migrate=") {
+                policy.mode = match mode {
+                    "ask" => Mode::Ask,
+                    "auto" => Mode::Auto,
+                    "abort" => Mode::Abort,
+                    "defer" => Mode::Defer,
+                    _ => {
+                        warnings.push(format!(
+                            "Invalid kde-linux.migrate={mode}, expected ask, auto, abort or defer. Using ask."
+                        ));
+                        Mode::Ask
+                    }
+                };
+            } else if let Some(keep) = word.strip_prefix("kde-linux.migrate.keep-snapshots=") {
+                policy.keep_snapshots = parse_bool(keep).unwrap_or_else(|| {
+                    warnings.push(format!(
+                        "Invalid kde-linux.migrate.keep-snapshots={keep}, expected yes or no. Using no."
+                    ));
+                    false
+                });
+            } else if let Some(list) = word.strip_prefix("kde-linux.migrate.user-subvolumes=") {
+                policy.user_subvolumes = nested::parse(list).unwrap_or_else(|e| {
+                    warnings.push(format!(
+                        "Invalid kde-linux.migrate.user-subvolumes={list}: {e}. Using all."
+                    ));
+                    nested::ALL.to_vec()
+                });
+            }
+        }
+        (policy, warnings)
+    }
+
+    pub fn from_cmdline() -> Self {
+        let cmdline = fs::read_to_string("/proc/cmdline").unwrap_or_else(|e| {
+            journal::warning(
+                &format!("Failed to read /proc/cmdline: {e}. Using the default migration policy."),
+                &[],
+            );
+            String::new()
+        });
+        let (policy, warnings) = Self::parse(&cmdline);
+        for warning in warnings {
+            journal::warning(&warning, &[]);
+        }
+        println!(
+            "Migration policy: kde-linux.migrate={} kde-linux.migrate.keep-snapshots={} \
+             kde-linux.migrate.user-subvolumes={}",
+            policy.mode,
+            if policy.keep_snapshots { "yes" } else { "no" },
+            nested::list(&policy.user_subvolumes)
+        );
+        policy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults() {
+        let (policy, warnings) = Policy::parse("BOOT_IMAGE=/vmlinuz quiet splash");
+        assert!(policy.mode == Mode::Ask);
+        assert!(!policy.keep_snapshots);
+        assert_eq!(nested::list(&policy.user_subvolumes), "cache,vms,games");
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn options() {
+        let (policy, warnings) = Policy::parse(
+            "quiet kde-linux.migrate=defer kde-linux.migrate.keep-snapshots=yes \
+             kde-linux.migrate.user-subvolumes=games,cache",
+        );
+        assert!(policy.mode == Mode::Defer);
+        assert!(policy.keep_snapshots);
+        assert_eq!(nested::list(&policy.user_subvolumes), "games,cache");
+        assert!(warnings.is_empty());
+
+        // Later ones win.
+        let (policy, _) = Policy::parse("kde-linux.migrate=abort kde-linux.migrate=auto");
+        assert!(policy.mode == Mode::Auto);
+        let (policy, _) = Policy::parse("kde-linux.migrate.user-subvolumes=none");
+        assert!(policy.user_subvolumes.is_empty());
+    }
+
+    #[test]
+    fn typos_fall_back_to_the_default() {
+        let (policy, warnings) = Policy::parse(
+            "kde-linux.migrate=auot kde-linux.migrate.keep-snapshots=maybe \
+             kde-linux.migrate.user-subvolumes=cache,vm",
+        );
+        assert!(policy.mode == Mode::Ask);
+        assert!(!policy.keep_snapshots);
+        assert_eq!(nested::list(&policy.user_subvolumes), "cache,vms,games");
+        assert_eq!(warnings.len(), 3);
+        assert!(warnings[0].contains("kde-linux.migrate=auot"));
+
+        // Only the broken option falls back.
+        let (policy, warnings) =
+            Policy::parse("kde-linux.migrate=auto kde-linux.migrate.keep-snapshots=2");
+        assert!(policy.mode == Mode::Auto);
+        assert_eq!(warnings.len(), 1);
+    }
+}
@@ -0,0 +1,110 @@
+// SPDX-License-Identifier: GPL-2.0-only OR GPL-3.0-only OR LicenseRef-KDE-Accepted-GPL
+// SPDX-FileCopyrightText: None
+
+// Questions for the user in the middle of boot. Asked on the splash when plymouth is up, so the migration stays
+// inside the graphical boot and does not depend on the console being on the active VT or the keymap being set up.
+// The tty is the fallback.
+
+use std::{
+    error::Error,
+    fs::File,
+    io::{self, Read, Write},
+    os::fd::AsFd,
+};
+
+use crate::{
+    cancel::{checkpoint, readable},
+    events,
+    help::Context,
+    plymouth,
+    policy::{Mode, Policy},
+};
+
+const NEXT_BOOT: &str = "Nothing was migrated. The next boot asks again.";
+
+pub struct Question<'a> {
+    // Has to fit the splash, which takes about 250 bytes including the prompt.
+    pub summary: &'a str,
+    // The full explanation for the console, where space is no concern.
+    pub details: &'a str,
+    // Shown as QR code on the console.
+    pub help_url: &'a str,
+    pub prompt: &'a str,
+}
+
+// Asks unless the kernel command line already decided for us. Callers handle kde-linux.migrate=defer before there is
+// anything to ask.
+pub fn confirm(policy: &Policy, question: &Question) -> Result<bool, Box<dyn Error>> {
+    events::prompt(question.summary, question.prompt);
+    match policy.mode {
+        Mode::Auto => {
+            println!("{}", question.details);
+            println!("{} yes (kde-linux.migrate=auto)", question.prompt);
+            events::prompt_answered(question.prompt, true, "policy");
+            return Ok(true);
+        }
+        Mode::Abort => {
+            println!("{}", question.details);
+            return Err(format!(
+                "Not asking \"{}\" because of kde-linux.migrate=abort",
+                question.prompt
+            )
+            .into());
+        }
+        Mode::Ask | Mode::Defer => {}
+    }
+
+    if let Some(answer) =
+        plymouth::ask_yes_no(&format!("{}\n{} [y/n]", question.summary, question.prompt))?
+    {
+        println!("{} {}", question.prompt, if answer { "yes" } else { "no" });
+        events::prompt_answered(question.prompt, answer, "splash");
+        return Ok(answer);
+    }
+
+    plymouth::pause_progress();
+    plymouth::hide_splash();
+
+    let _ = qr2term::print_qr(question.help_url);
+    println!("{}", question.details);
+    io::stdout().flush().unwrap();
+
+    let answer = ask_tty(question.prompt)?;
+
+    events::prompt_answered(question.prompt, answer, "console");
+    plymouth::show_splash();
+    plymouth::unpause_progress();
+    Ok(answer)
+}
+
+// y or n from the tty. In line mode, so Ctrl-C is a SIGINT like everywhere else, and in bounded polls, so that it stops
+// us at checkpoint() rather than waiting for a read that never returns, see cancel.rs.
+fn ask_tty(prompt: &str) -> Result<bool, Box<dyn Error>> {
+    // Unbuffered, whatever we did not read yet has to stay where poll() sees it. In line mode a read returns at most
+    // one line.
+    let mut tty = File::from(io::stdin().as_fd().try_clone_to_owned()?);
+    loop {
+        print!("{prompt} [y/n] ");
+        io::stdout().flush()?;
+        let mut line = Vec::new();
+        while line.last() != Some(&b'\n') {
+            while !readable(tty.as_fd()).context(|| "Prompt aborted")? {
+                checkpoint(NEXT_BOOT)?;
+            }
+            let mut buffer = [0u8; 256];
+            match tty.read(&mut buffer).context(|| "Prompt aborted")? {
+                0 => return Err("Prompt aborted: end of input".into()),
+                read => line.extend_from_slice(&buffer[..read]),
+            }
+        }
+        match String::from_utf8_lossy(&line)
+            .trim()
+            .to_lowercase()
+            .as_str()
+        {
+            "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,157 @@
+// SPDX-License-Identifier: GPL-2.0-only OR GPL-3.0-only OR LicenseRef-KDE-Accepted-GPL
+// SPDX-FileCopyrightText: None
+
+// A record of the migration that survives the initrd: @system/var/lib/kde-linux/migration-v<to>.json. Fed by the
+// journal events, read by /usr/lib/migration-report to tell users about it on their first login. Runs that end up
+// skipping the migration leave no report, they would replace the one of the run that did migrate before the user got
+// to see it.
+
+use std::{
+    fs,
+    io::Write,
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{Value, json};
+
+use crate::durability::fsync_dir;
+
+// Bump when changing the meaning of existing keys. Adding keys is fine.
+const SCHEMA: u32 = 1;
+
+struct Report {
+    from: u8,
+    to: u8,
+    started_at: u64,
+    // finished, skipped or failed, and what the migrator had to say about it. Skipped ones are not written.
+    result: Option<(&'static str, String)>,
+    // See help.rs, only set on failure.
+    diagnostic: Option<&'static str>,
+    phases: Vec<Value>,
+    // Apparent size of what the phases moved. Most of it is reflinked or snapshotted rather than copied.
+    data_size: u64,
+    users: Vec<String>,
+    warnings: Vec<Value>,
+    leftovers: Vec<String>,
+}
+
+static REPORT: Mutex<Option<Report>> = Mutex::new(None);
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn with(f: impl FnOnce(&mut Report)) {
+    if let Some(report) = REPORT.lock().unwrap().as_mut() {
+        f(report);
+    }
+}
+
+pub fn started(from: u8, to: u8) {
+    *REPORT.lock().unwrap() = Some(Report {
+        from,
+        to,
+        started_at: now(),
+        result: None,
+        diagnostic: None,
+        phases: Vec::new(),
+        data_size: 0,
+        users: Vec::new(),
+        warnings: Vec::new(),
+        leftovers: Vec::new(),
+    });
+}
+
+pub fn phase(name: &str, duration_usec: u128, path: Option<String>, bytes: Option<u64>) {
+    with(|report| {
+        report.data_size += bytes.unwrap_or(0);
+        report.phases.push(json!({
+            "name": name,
+            "duration_usec": duration_usec as u64,
+            "path": path,
+            "bytes": bytes,
+        }));
+    });
+}
+
+pub fn warning(message: &str, path: Option<String>) {
+    with(|report| {
+        report
+            .warnings
+            .push(json!({ "message": message, "path": path }))
+    });
+}
+
+pub fn result(result: &'static str, message: &str) {
+    with(|report| report.result = Some((result, message.to_string())));
+}
+
+pub fn diagnostic(code: &'static str) {
+    with(|report| report.diagnostic = Some(code));
+}
+
+pub fn user(name: &str) {
+    with(|report| report.users.push(name.to_string()));
+}
+
+// Data the migration left in place that the user may want to look at or delete, relative to the btrfs top level.
+pub fn leftover(root: &Path, path: &Path) {
+    let path = path.strip_prefix(root).unwrap_or(path);
+    with(|report| report.leftovers.push(path.display().to_string()));
+}
+
+// Next to the migrated system, when there is one to write into.
+pub fn write(root: &Path) {
+    let Some(report) = REPORT.lock().unwrap().take() else {
+        return;
+    };
+    if let Some(("skipped", _)) = report.result {
+        println!("Skipped the migration, not writing a report.");
+        return;
+    }
+    let system = root.join("@system");
+    if !system.exists() {
+        println!("No @system to write the migration report into.");
+        return;
+    }
+
+    let (result, message) = report.result.unwrap_or(("failed", String::new()));
+    let value = json!({
+        "schema": SCHEMA,
+        "from_layout": report.from,
+        "to_layout": report.to,
+        "result": result,
+        "message": message,
+        "diagnostic": report.diagnostic,
+        "started_at": report.started_at,
+        "finished_at": now(),
+        "phases": report.phases,
+        "data_size": report.data_size,
+        "users": report.users,
+        "warnings": report.warnings,
+        "leftovers": report.leftovers,
+    });
+
+    let dir = system.join("var/lib/kde-linux");
+    let path = dir.join(format!("migration-v{}.json", report.to));
+    let tmp = dir.join(format!(".migration-v{}.json.tmp", report.to));
+    let result = fs::create_dir_all(&dir)
+        .and_then(|_| {
+            let mut file = fs::File::create(&tmp)?;
+            serde_json::to_writer_pretty(&mut file, &value)?;
+            file.write_all(b"\n")?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp, &path))
+        .and_then(|_| fsync_dir(&dir));
+    match result {
+        Ok(_) => println!("Wrote migration report {path:?}"),
+        // Not worth failing the boot over.
+        Err(e) => eprintln!("Failed to write migration report {path:?}: {e}"),
+    }
+}
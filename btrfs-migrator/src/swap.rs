@@ -0,0 +1,92 @@
+// SPDX-License-Identifier: GPL-2.0-only OR GPL-3.0-only OR LicenseRef-KDE-Accepted-GPL
+// SPDX-FileCopyrightText: None
+
+// swapon refuses swapfiles on btrfs that are copy-on-write, compressed or share extents with a snapshot. The copies cp
+// and the snapshots make of the v1 swapfiles are all of that. So they are recreated from scratch in a swap subvolume of
+// their own, which no snapshot of @system includes, and fstab points there instead. See fstab.rs.
+
+use std::{error::Error, fs, io, path::Path, process::Command};
+
+use libbtrfsutil::CreateSubvolumeOptions;
+
+use crate::{attributes::set_nocow, durability::fsync_dir, help::Context, journal};
+
+// In @system, so /swap on the running system.
+pub const SUBVOLUME: &str = "swap";
+
+// The name in SUBVOLUME for the v1 swapfile at `path`, safe for fstab as is.
+pub fn name(path: &str) -> String {
+    path.trim_start_matches('/')
+        .chars()
+        .map(|c| {
+            if c == '/' || c.is_whitespace() {
+                '-'
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+// Replaces the copies of the v1 `swapfiles` in `system` by fresh ones of the same size in SUBVOLUME. Returns those it
+// recreated, for fstab to point at.
+pub fn migrate<'a>(system: &Path, swapfiles: &[&'a str]) -> Result<Vec<&'a str>, Box<dyn Error>> {
+    if swapfiles.is_empty() {
+        return Ok(Vec::new());
+    }
+    let dir = system.join(SUBVOLUME);
+    if !dir.exists() {
+        println!("Creating swap subvolume {dir:?}");
+        CreateSubvolumeOptions::new()
+            .create(&dir)
+            .context(|| format!("Problem creating subvolume {dir:?}"))?;
+    }
+    // For whatever swapfiles the user adds later.
+    set_nocow(&dir).context(|| format!("Failed to disable copy-on-write for {dir:?}"))?;
+
+    let mut recreated = Vec::new();
+    for swapfile in swapfiles {
+        let copy = system.join(swapfile.trim_start_matches('/'));
+        let size = match fs::metadata(&copy) {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                journal::warning(
+                    &format!("The swapfile {swapfile} does not exist, there is no swap to migrate"),
+                    &[("PATH", swapfile)],
+                );
+                continue;
+            }
+            Err(e) => return Err(e).context(|| format!("Failed to read {copy:?}")),
+        };
+        let new = dir.join(name(swapfile));
+        println!("Recreating swapfile {swapfile} as {new:?} ({size} bytes)");
+        // NOCOW, preallocated and formatted.
+        let status = Command::new("btrfs")
+            .arg("filesystem")
+            .arg("mkswapfile")
+            .arg("--size")
+            .arg(size.to_string())
+            .arg(&new)
+            .status()
+            .context(|| "Failed to run btrfs filesystem mkswapfile")?;
+        if !status.success() {
+            return Err(format!("Failed to create swapfile {new:?}").into());
+        }
+        fs::remove_file(&copy)?;
+        println!(
+            "Recreated the swapfile {swapfile} as /{SUBVOLUME}/{}",
+            name(swapfile)
+        );
+        recreated.push(*swapfile);
+    }
+    fsync_dir(&dir)?;
+
+    // The offset of the swapfile changed along with it.
+    if fs::read_to_string("/proc/cmdline").is_ok_and(|cmdline| cmdline.contains("resume_offset=")) {
+        journal::warning(
+            "Hibernation resumes from a swapfile. Its resume_offset= on the kernel command line is outdated now.",
+            &[],
+        );
+    }
+    Ok(recreated)
+}
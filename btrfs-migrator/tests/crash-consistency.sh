@@ -0,0 +1,95 @@
+#!/bin/sh
+# SPDX-License-Identifier: GPL-2.0-only OR GPL-3.0-only OR LicenseRef-KDE-Accepted-GPL
+# SPDX-FileCopyrightText: None
+
+# Power-loss test for the v2->v3 migration. Runs the migrator on a btrfs on top of dm-log-writes, then replays the
+# recorded writes prefix by prefix onto a fresh device. Every prefix is a possible state after a power cut, so at each
+# one we mount a throwaway snapshot of it, let the migrator recover and finish, and compare the homes against what
+# we started with.
+#
+# Needs root, dm-log-writes, dm-snapshot, btrfs-progs and replay-log from xfstests (src/log-writes/replay-log).
+#
+# Usage: sudo tests/crash-consistency.sh [btrfs-migrator]
+#   REPLAY_LOG  path to replay-log (default: from PATH)
+#   CHECK       replay-log --check argument: 1 checks every write prefix (default), flush or fua only check at
+#               barriers, which is a lot faster.
+
+set -eu
+
+name=kde-linux-crash
+tests=$(dirname "$(realpath "$0")")
+. "$tests/lib.sh"
+
+# Called by replay-log for every prefix, with the replay device as argument.
+check() {
+    device=$1
+    dmsetup create "$name-check" --table "0 $(blockdev --getsz "$device") snapshot $device $COW N 8"
+    trap 'umount "$WORK/check" 2>/dev/null; dmsetup remove "$name-check"' EXIT
+    mount "/dev/mapper/$name-check" "$WORK/check" || fail "prefix does not mount"
+
+    "$MIGRATOR" "$WORK/check" > "$WORK/check.log" 2>&1 || {
+        cat "$WORK/check.log" >&2
+        fail "migrator did not recover"
+    }
+
+    check_v3 "$WORK/check" "$WORK/manifest"
+}
+
+if [ "${1:-}" = check ]; then
+    check "$2"
+    exit 0
+fi
+
+migrator=$(realpath "${1:-$tests/../target/debug/btrfs-migrator}")
+replay_log=${REPLAY_LOG:-replay-log}
+
+[ -x "$migrator" ] || fail "$migrator not found, build it first"
+command -v "$replay_log" > /dev/null || fail "replay-log not found, set REPLAY_LOG"
+
+WORK=$(mktemp --directory)
+mkdir "$WORK/mnt" "$WORK/check"
+cleanup() {
+    umount "$WORK/mnt" 2>/dev/null || true
+    dmsetup remove "$name-lw" 2>/dev/null || true
+    for loop in ${DATA:-} ${LOG:-} ${REPLAY:-} ${COW:-}; do
+        losetup --detach "$loop"
+    done
+    rm -rf "$WORK"
+}
+trap cleanup EXIT
+
+for img in data log replay; do
+    truncate --size=2G "$WORK/$img.img"
+done
+truncate --size=1G "$WORK/cow.img"
+DATA=$(losetup --find --show "$WORK/data.img")
+LOG=$(losetup --find --show "$WORK/log.img")
+REPLAY=$(losetup --find --show "$WORK/replay.img")
+COW=$(losetup --find --show "$WORK/cow.img")
+
+dmsetup create "$name-lw" --table "0 $(blockdev --getsz "$DATA") log-writes $DATA $LOG"
+lw="/dev/mapper/$name-lw"
+mkfs.btrfs --quiet "$lw"
+
+mount "$lw" "$WORK/mnt"
+make_v2 "$WORK/mnt"
+manifest "$WORK/mnt/@system/home" > "$WORK/manifest"
+umount "$WORK/mnt"
+dmsetup message "$name-lw" 0 mark setup
+
+mount "$lw" "$WORK/mnt"
+"$migrator" "$WORK/mnt"
+umount "$WORK/mnt"
+dmsetup message "$name-lw" 0 mark done
+dmsetup remove "$name-lw"
+
+export WORK COW
+export MIGRATOR="$migrator"
+# --start-mark skips the writes before the mark rather than replaying them unchecked, so lay down the v2 setup first.
+"$replay_log" --log "$LOG" --replay "$REPLAY" --end-mark setup
+"$replay_log" --log "$LOG" --replay "$REPLAY" --start-mark setup --end-mark done \
+    --check "${CHECK:-1}" --fsck "$tests/crash-consistency.sh check $REPLAY"
+# And the end state, in case the last write was not a check point.
+"$tests/crash-consistency.sh" check "$REPLAY"
+
+echo "PASS: every replayed prefix recovered"
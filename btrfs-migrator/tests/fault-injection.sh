@@ -0,0 +1,216 @@
+#!/bin/sh
+# SPDX-License-Identifier: GPL-2.0-only OR GPL-3.0-only OR LicenseRef-KDE-Accepted-GPL
+# SPDX-FileCopyrightText: None
+
+# Drives every recovery branch of the migrator. For each fault point the migration is made to fail there, once with
+# an error and once with an abort, then the filesystem is remounted as a reboot would and the next run has to finish
+# the job with all homes intact. The states older migrators could leave behind are constructed by hand.
+#
+# Needs root and btrfs-progs.
+#
+# Usage: sudo tests/fault-injection.sh [btrfs-migrator]
+
+set -eu
+
+tests=$(dirname "$(realpath "$0")")
+. "$tests/lib.sh"
+
+migrator=$(realpath "${1:-$tests/../target/debug/btrfs-migrator}")
+[ -x "$migrator" ] || fail "$migrator not found, build it first"
+
+WORK=$(mktemp --directory)
+mnt="$WORK/mnt"
+mkdir "$mnt"
+cleanup() {
+    umount --recursive "$mnt" 2>/dev/null || true
+    [ -z "${DEV:-}" ] || losetup --detach "$DEV"
+    rm -rf "$WORK"
+}
+trap cleanup EXIT
+
+truncate --size=1G "$WORK/disk.img"
+DEV=$(losetup --find --show "$WORK/disk.img")
+
+fresh() {
+    umount --recursive "$mnt" 2>/dev/null || true
+    mkfs.btrfs --quiet --force "$DEV"
+    mount "$DEV" "$mnt"
+}
+
+# Whatever a crash left mounted is gone after a reboot.
+reboot() {
+    umount --recursive "$mnt"
+    mount "$DEV" "$mnt"
+}
+
+migrate() {
+    "$migrator" "$@" > "$WORK/log" 2>&1 || {
+        cat "$WORK/log" >&2
+        return 1
+    }
+}
+
+# inject <point> <mode> <migrator args...>
+# Run with a fault, then reboot. The run has to fail, otherwise the point was never reached.
+inject() {
+    fault="$1:$2"
+    shift 2
+    if KDE_LINUX_MIGRATOR_FAULT="$fault" "$migrator" "$@" > "$WORK/log" 2>&1; then
+        fail "$fault was not hit"
+    fi
+    reboot
+}
+
+for point in v2-after-create-import v2-after-copy-etc v2-after-copy-var v2-after-snapshot-@home \
+    v2-after-snapshot-@docker v2-before-rename-import v2-after-rename-import; do
+    for mode in error abort; do
+        echo "v1->v3 with $point:$mode"
+        fresh
+        make_v1 "$mnt"
+        manifest "$mnt/@home" > "$WORK/manifest"
+        inject "$point" "$mode" "$mnt"
+        # As on boot: v1->v2 first, the next boot does v2->v3.
+        [ -e "$mnt/@system" ] || migrate "$mnt" || fail "v2 did not recover from $point:$mode"
+        reboot
+        migrate "$mnt" || fail "v3 did not run after $point:$mode"
+        [ ! -e "$mnt/@system.import" ] || fail "@system.import left behind"
+        [ -f "$mnt/@system/etc/hostname" ] || fail "etc overlay not carried over"
+        is_subvolume "$mnt/@system/etc" || fail "etc is not a subvolume"
+        check_v3 "$mnt" "$WORK/manifest"
+    done
+done
+
+for point in v3-after-create-tmp v3-before-copy-alice v3-alice-.cache-after-create-tmp \
+    v3-alice-.local-share-libvirt-images-before-exchange v3-alice-VirtualBox-VMs-after-exchange v3-before-copy-bob \
+    v3-before-exchange v3-after-exchange v3-after-rename-tmp-to-old; do
+    for mode in error abort; do
+        echo "v2->v3 with $point:$mode"
+        fresh
+        make_v2 "$mnt"
+        manifest "$mnt/@system/home" > "$WORK/manifest"
+        inject "$point" "$mode" "$mnt"
+        migrate "$mnt" || fail "did not recover from $point:$mode"
+        check_v3 "$mnt" "$WORK/manifest"
+    done
+done
+
+for point in rollback-after-create-tmp rollback-before-copy-alice rollback-before-copy-bob \
+    rollback-before-exchange rollback-after-exchange rollback-after-rename-tmp-to-old; do
+    for mode in error abort; do
+        echo "v3->v2 with $point:$mode"
+        fresh
+        make_v2 "$mnt"
+        manifest "$mnt/@system/home" > "$WORK/manifest"
+        migrate "$mnt"
+        inject "$point" "$mode" rollback "$mnt"
+        # An interrupted rollback must not be migrated over.
+        if migrate "$mnt" 2>/dev/null; then
+            fail "v3 migration ran over the interrupted rollback"
+        fi
+        migrate rollback "$mnt" || fail "rollback did not recover from $point:$mode"
+        check_v2 "$mnt" "$WORK/manifest"
+    done
+done
+
+for point in v4-flatpak-after-create-tmp v4-flatpak-before-exchange v4-flatpak-after-exchange; do
+    for mode in error abort; do
+        echo "v3->v6 with $point:$mode"
+        fresh
+        make_v3 "$mnt"
+        manifest "$mnt/@system/var/lib/flatpak" > "$WORK/manifest"
+        inject "$point" "$mode" "$mnt"
+        migrate "$mnt" || fail "did not recover from $point:$mode"
+        check_converted "$mnt" @system/var/lib/flatpak "$WORK/manifest"
+    done
+done
+
+for point in v5-log-after-create-tmp v5-log-before-exchange v5-log-after-exchange v5-cache-before-exchange \
+    v5-tmp-after-exchange; do
+    for mode in error abort; do
+        echo "v3->v6 with $point:$mode"
+        fresh
+        make_v3 "$mnt"
+        for dir in log cache tmp; do
+            manifest "$mnt/@system/var/$dir" > "$WORK/manifest-$dir"
+        done
+        inject "$point" "$mode" "$mnt"
+        migrate "$mnt" || fail "did not recover from $point:$mode"
+        for dir in log cache tmp; do
+            check_converted "$mnt" "@system/var/$dir" "$WORK/manifest-$dir"
+        done
+        lsattr -d "$mnt/@system/var/log/journal" | cut -d' ' -f1 | grep -q C || fail "journal is copy-on-write"
+        lsattr "$mnt/@system/var/log/journal/0123/system.journal" | cut -d' ' -f1 | grep -q C ||
+            fail "existing journal file is copy-on-write"
+    done
+done
+
+for point in v6-etc-after-create-tmp v6-etc-before-exchange v6-etc-after-exchange; do
+    for mode in error abort; do
+        echo "v3->v6 with $point:$mode"
+        fresh
+        make_v3 "$mnt"
+        manifest "$mnt/@system/etc" > "$WORK/manifest"
+        inject "$point" "$mode" "$mnt"
+        migrate "$mnt" || fail "did not recover from $point:$mode"
+        check_converted "$mnt" @system/etc "$WORK/manifest"
+    done
+done
+
+echo "v3->v6 in a single run"
+fresh
+make_v3 "$mnt"
+migrate "$mnt"
+for path in var/lib/flatpak var/log var/cache var/tmp etc; do
+    is_subvolume "$mnt/@system/$path" || fail "$path is not a subvolume after one run"
+done
+migrate "$mnt"
+grep -q "Nothing to do" "$WORK/log" || fail "a second run found work left"
+
+echo "v2->v3 with home only in home.v3old, as left by older migrators"
+fresh
+make_v2 "$mnt"
+manifest "$mnt/@system/home" > "$WORK/manifest"
+mv "$mnt/@system/home" "$mnt/@system/home.v3old"
+mkdir -p "$mnt/@system/home.v3tmp/alice"
+migrate "$mnt" || fail "did not restore home from home.v3old"
+check_v3 "$mnt" "$WORK/manifest"
+
+echo "v2->v3 with both home and home.v3old as subvolumes"
+fresh
+make_v2 "$mnt"
+btrfs -q subvolume create "$mnt/@system/home.v3old"
+if migrate "$mnt" 2>/dev/null; then
+    fail "migrated although home and home.v3old are both subvolumes"
+fi
+is_subvolume "$mnt/@system/home" || fail "home was touched"
+[ -e "$mnt/@system/home.v3old" ] || fail "home.v3old was touched"
+
+echo "v2->v3 leftover home.v3old after a completed migration"
+fresh
+make_v2 "$mnt"
+manifest "$mnt/@system/home" > "$WORK/manifest"
+migrate "$mnt"
+btrfs -q subvolume create "$mnt/@system/home.v3old"
+migrate "$mnt" || fail "did not clean up home.v3old"
+check_v3 "$mnt" "$WORK/manifest"
+
+for point in user-subvolumes-.local-share-Steam-steamapps-after-create-tmp \
+    user-subvolumes-.local-share-Steam-steamapps-before-exchange \
+    user-subvolumes-.local-share-Steam-steamapps-after-exchange; do
+    for mode in error abort; do
+        echo "user-subvolumes with $point:$mode"
+        fresh
+        make_v2 "$mnt"
+        migrate "$mnt"
+        steamapps="$mnt/@system/home/alice/.local/share/Steam/steamapps"
+        mkdir -p "$steamapps/common/game"
+        head --bytes=1M /dev/urandom > "$steamapps/common/game/data.pak"
+        manifest "$steamapps" > "$WORK/manifest"
+        inject "$point" "$mode" user-subvolumes --categories=games "$mnt/@system/home/alice"
+        migrate user-subvolumes --categories=games "$mnt/@system/home/alice" || fail "did not recover from $point:$mode"
+        check_converted "$mnt" @system/home/alice/.local/share/Steam/steamapps "$WORK/manifest"
+        lsattr -d "$steamapps" | cut -d' ' -f1 | grep -q C || fail "steamapps is copy-on-write"
+    done
+done
+
+echo "PASS: every fault point recovered"
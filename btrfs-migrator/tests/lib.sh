@@ -0,0 +1,125 @@
+# SPDX-License-Identifier: GPL-2.0-only OR GPL-3.0-only OR LicenseRef-KDE-Accepted-GPL
+# SPDX-FileCopyrightText: None
+
+# Fixtures and checks shared by the test scripts. Everything takes the mounted btrfs top level as first argument.
+
+is_subvolume() {
+    [ "$(stat --format=%i "$1")" = 256 ]
+}
+
+manifest() {
+    (
+        cd "$1"
+        find . -printf '%y %m %U:%G %p %l\n' | sort
+        find . -type f -exec sha256sum {} + | sort
+    )
+}
+
+fail() {
+    echo "FAIL: $*" >&2
+    exit 1
+}
+
+# Homes as they look in the v2 layout: users as plain directories, one of them with a nested subvolume, a dangling
+# symlink, a cache and VM images, plus a stray file next to the users. The other has none of what v3 turns into
+# nested subvolumes.
+make_homes() {
+    home=$1
+    mkdir -p "$home/alice/.local/share/libvirt/images" "$home/alice/Documents" "$home/alice/.cache/thumbnails" \
+        "$home/alice/VirtualBox VMs/test" "$home/bob"
+    echo "thumbnail" > "$home/alice/.cache/thumbnails/note.png"
+    head --bytes=2M /dev/urandom > "$home/alice/.local/share/libvirt/images/vm.qcow2"
+    head --bytes=1M /dev/urandom > "$home/alice/VirtualBox VMs/test/test.vdi"
+    echo "hello" > "$home/alice/Documents/note.txt"
+    ln -s /nonexistent "$home/alice/.steam"
+    btrfs -q subvolume create "$home/alice/.local/share/containers"
+    mkdir "$home/alice/.local/share/containers/storage"
+    head --bytes=1M /dev/urandom > "$home/alice/.local/share/containers/storage/layer"
+    head --bytes=16M /dev/urandom > "$home/bob/big"
+    echo "not a user" > "$home/README"
+    chown -R 1000:1000 "$home/alice"
+    chown -R 1001:1001 "$home/bob"
+}
+
+# v2: @system with home as a subvolume.
+make_v2() {
+    btrfs -q subvolume create "$1/@system"
+    btrfs -q subvolume create "$1/@system/home"
+    make_homes "$1/@system/home"
+}
+
+# v1: a rootfs subvolume composed with etc and var overlays, plus the data subvolumes that v2 snapshots into @system.
+make_v1() {
+    btrfs -q subvolume create "$1/@kde-linux_1"
+    mkdir "$1/@kde-linux_1/etc" "$1/@kde-linux_1/var"
+    echo "ID=kde-linux" > "$1/@kde-linux_1/etc/os-release"
+    for dir in etc var; do
+        mkdir -p "$1/@$dir-overlay/upper" "$1/@$dir-overlay/work"
+    done
+    echo "kde-linux" > "$1/@etc-overlay/upper/hostname"
+    mkdir -p "$1/@var-overlay/upper/lib/containers"
+    echo "stale" > "$1/@var-overlay/upper/lib/containers/predates-subvolume"
+    for subvol in @home @root @containers @docker; do
+        btrfs -q subvolume create "$1/$subvol"
+    done
+    make_homes "$1/@home"
+    echo "root" > "$1/@root/.bashrc"
+}
+
+# v3 as far as the later layouts care: flatpak data, logs, caches, temporary files and etc in plain directories.
+make_v3() {
+    btrfs -q subvolume create "$1/@system"
+    mkdir -p "$1/@system/home" "$1/@system/var/lib/flatpak/repo/objects" "$1/@system/var/log/journal/0123" \
+        "$1/@system/var/cache/fontconfig" "$1/@system/var/tmp"
+    head --bytes=4M /dev/urandom > "$1/@system/var/lib/flatpak/repo/objects/blob"
+    ln -s repo "$1/@system/var/lib/flatpak/current"
+    chmod 0700 "$1/@system/var/lib/flatpak/repo"
+    head --bytes=1M /dev/urandom > "$1/@system/var/log/journal/0123/system.journal"
+    echo "cache" > "$1/@system/var/cache/fontconfig/cache"
+    chmod 1777 "$1/@system/var/tmp"
+    mkdir -p "$1/@system/etc/systemd/system"
+    echo "kde-linux" > "$1/@system/etc/hostname"
+    ln -s /dev/null "$1/@system/etc/systemd/system/masked.service"
+}
+
+# <top level> <path> <manifest>: the directory at path is a subvolume now, with the content of the manifest.
+check_converted() {
+    is_subvolume "$1/$2" || fail "$2 is not a subvolume"
+    [ ! -e "$1/$2.subvoltmp" ] || fail "$2.subvoltmp left behind"
+    manifest "$1/$2" | diff -u "$3" - >&2 || fail "$2 content differs"
+}
+
+# v3: home is a directory, every user a subvolume. `manifest` is the file to compare the homes against.
+check_v3() {
+    home="$1/@system/home"
+    [ -d "$home" ] || fail "home is missing"
+    ! is_subvolume "$home" || fail "home is still a subvolume"
+    [ ! -e "$1/@system/home.v3old" ] || fail "home.v3old left behind"
+    [ ! -e "$1/@system/home.v3tmp" ] || fail "home.v3tmp left behind"
+    for user in alice bob; do
+        is_subvolume "$home/$user" || fail "$user is not a subvolume"
+    done
+    is_subvolume "$home/alice/.local/share/containers" || fail "nested subvolume lost"
+    for path in alice/.cache alice/.local/share/libvirt/images "alice/VirtualBox VMs"; do
+        is_subvolume "$home/$path" || fail "$path is not a subvolume"
+        [ ! -e "$home/$path.subvoltmp" ] || fail "$path.subvoltmp left behind"
+    done
+    for path in bob/.cache "bob/VirtualBox VMs"; do
+        [ ! -e "$home/$path" ] || fail "$path was created"
+    done
+    lsattr -d "$home/alice/.local/share/libvirt/images" | cut -d' ' -f1 | grep -q C || fail "VM images are copy-on-write"
+    manifest "$home" | diff -u "$2" - >&2 || fail "home content differs"
+}
+
+# v2: home is a single subvolume again.
+check_v2() {
+    home="$1/@system/home"
+    is_subvolume "$home" || fail "home is not a subvolume"
+    [ ! -e "$1/@system/home.v2old" ] || fail "home.v2old left behind"
+    [ ! -e "$1/@system/home.v2tmp" ] || fail "home.v2tmp left behind"
+    for user in alice bob; do
+        ! is_subvolume "$home/$user" || fail "$user is still a subvolume"
+    done
+    is_subvolume "$home/alice/.local/share/containers" || fail "nested subvolume lost"
+    manifest "$home" | diff -u "$2" - >&2 || fail "home content differs"
+}
@@ -0,0 +1,115 @@
+#!/bin/sh
+# SPDX-License-Identifier: GPL-2.0-only OR GPL-3.0-only OR LicenseRef-KDE-Accepted-GPL
+# SPDX-FileCopyrightText: None
+
+# Mounts where the migrator renames and deletes. Leftovers inside home have to be unmounted before anything is
+# copied, busy ones and homes mounted elsewhere have to make the migration refuse without touching anything. On a
+# running system nothing is a leftover: user-subvolumes has to leave a user's own mounts alone.
+#
+# Needs root and btrfs-progs.
+#
+# Usage: sudo tests/mounts.sh [btrfs-migrator]
+
+set -eu
+
+tests=$(dirname "$(realpath "$0")")
+. "$tests/lib.sh"
+
+migrator=$(realpath "${1:-$tests/../target/debug/btrfs-migrator}")
+[ -x "$migrator" ] || fail "$migrator not found, build it first"
+
+WORK=$(mktemp --directory)
+mnt="$WORK/mnt"
+mkdir "$mnt" "$WORK/elsewhere"
+cleanup() {
+    [ -z "${SLEEPER:-}" ] || kill "$SLEEPER"
+    umount --recursive "$WORK/elsewhere" 2>/dev/null || true
+    umount --recursive "$mnt" 2>/dev/null || true
+    [ -z "${DEV:-}" ] || losetup --detach "$DEV"
+    rm -rf "$WORK"
+}
+trap cleanup EXIT
+
+truncate --size=1G "$WORK/disk.img"
+DEV=$(losetup --find --show "$WORK/disk.img")
+
+fresh() {
+    umount --recursive "$mnt" 2>/dev/null || true
+    mkfs.btrfs --quiet --force "$DEV"
+    mount "$DEV" "$mnt"
+    make_v2 "$mnt"
+    manifest "$mnt/@system/home" > "$WORK/manifest"
+}
+
+echo "leftover mount inside home"
+fresh
+mkdir "$mnt/@system/home/alice/leftover"
+manifest "$mnt/@system/home" > "$WORK/manifest"
+mount -t tmpfs tmpfs "$mnt/@system/home/alice/leftover"
+touch "$mnt/@system/home/alice/leftover/not-copied"
+"$migrator" "$mnt" > "$WORK/log" 2>&1 || {
+    cat "$WORK/log" >&2
+    fail "did not unmount the leftover"
+}
+check_v3 "$mnt" "$WORK/manifest"
+
+echo "busy mount inside home"
+fresh
+mkdir "$mnt/@system/home/alice/busy"
+mount -t tmpfs tmpfs "$mnt/@system/home/alice/busy"
+(cd "$mnt/@system/home/alice/busy" && exec sleep 600) &
+SLEEPER=$!
+if "$migrator" "$mnt" > "$WORK/log" 2>&1; then
+    fail "migrated with a busy mount inside home"
+fi
+grep --quiet "could not be unmounted" "$WORK/log" || fail "no precise message: $(cat "$WORK/log")"
+is_subvolume "$mnt/@system/home" || fail "home was touched"
+kill "$SLEEPER"
+wait "$SLEEPER" || true
+SLEEPER=
+
+echo "home mounted elsewhere"
+fresh
+mount --bind "$mnt/@system/home/bob" "$WORK/elsewhere"
+if "$migrator" "$mnt" > "$WORK/log" 2>&1; then
+    fail "migrated while home is mounted elsewhere"
+fi
+grep --quiet "also mounted at" "$WORK/log" || fail "no precise message: $(cat "$WORK/log")"
+is_subvolume "$mnt/@system/home" || fail "home was touched"
+umount "$WORK/elsewhere"
+
+echo "rollback while @system is in use"
+fresh
+"$migrator" "$mnt" > "$WORK/log" 2>&1 || {
+    cat "$WORK/log" >&2
+    fail "did not migrate"
+}
+mount -o subvol=@system "$DEV" "$WORK/elsewhere"
+if "$migrator" rollback "$mnt" > "$WORK/log" 2>&1; then
+    fail "rolled back while @system is mounted elsewhere"
+fi
+grep --quiet "also mounted at" "$WORK/log" || fail "no precise message: $(cat "$WORK/log")"
+check_v3 "$mnt" "$WORK/manifest"
+umount "$WORK/elsewhere"
+
+echo "user-subvolumes with a disk mounted at steamapps"
+fresh
+"$migrator" "$mnt" > "$WORK/log" 2>&1 || {
+    cat "$WORK/log" >&2
+    fail "did not migrate"
+}
+steamapps="$mnt/@system/home/alice/.local/share/Steam/steamapps"
+mkdir -p "$steamapps"
+mount -t tmpfs tmpfs "$steamapps"
+touch "$steamapps/game.pak"
+"$migrator" user-subvolumes --categories=games "$mnt/@system/home/alice" > "$WORK/log" 2>&1 || {
+    cat "$WORK/log" >&2
+    fail "did not skip the mount"
+}
+grep --quiet "is mounted there" "$WORK/log" || fail "no precise message: $(cat "$WORK/log")"
+mountpoint --quiet "$steamapps" || fail "steamapps was unmounted"
+[ -e "$steamapps/game.pak" ] || fail "steamapps was touched"
+umount "$steamapps"
+! is_subvolume "$steamapps" || fail "steamapps was converted"
+
+echo "PASS: mounts handled"
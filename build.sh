@@ -85,6 +85,13 @@ pacman --sync --refresh --refresh --noconfirm
 cargo build --release --manifest-path btrfs-migrator/Cargo.toml
 cp -v btrfs-migrator/target/release/btrfs-migrator mkosi.extra/usr/lib/
 
+# Compile the translations, btrfs-migrator needs them in the initrd already.
+for po in po/*/kde-linux.po; do
+    lang=$(basename "$(dirname "$po")")
+    mkdir --parents "mkosi.extra/usr/share/locale/$lang/LC_MESSAGES"
+    msgfmt --output-file="mkosi.extra/usr/share/locale/$lang/LC_MESSAGES/kde-linux.mo" "$po"
+done
+
 rm --recursive --force kde-linux-sysupdated
 git clone https://invent.kde.org/kde-linux/kde-linux-sysupdated
 DESTDIR=$PWD/mkosi.extra make --directory=kde-linux-sysupdated install
@@ -258,6 +258,13 @@ def _create_subvolumes(state: InstallState) -> None:
     _run(["btrfs", "subvolume", "delete", f"{tmp}/@system/home"], check=False)
     for sub in ("boot", "proc", "sys", "dev", "run", "usr", "home"):
         (state.tmpdir / "@system" / sub).mkdir(parents=True, exist_ok=True)
+    # Kept out of @system snapshots, like btrfs-migrator's SPLIT_LAYOUTS do for older installs.
+    for sub in ("var/lib/flatpak", "var/log", "var/cache", "var/tmp"):
+        (state.tmpdir / "@system" / sub).parent.mkdir(parents=True, exist_ok=True)
+        _run(["btrfs", "subvolume", "create", f"{tmp}/@system/{sub}"])
+    # The journal writes in place, copy-on-write only fragments it. tmpfiles fixes up mode and group.
+    (state.tmpdir / "@system" / "var" / "log" / "journal").mkdir()
+    _run(["chattr", "+C", f"{tmp}/@system/var/log/journal"])
 
 
 def _copy_image(state: InstallState) -> None: